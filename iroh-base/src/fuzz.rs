@@ -0,0 +1,156 @@
+//! [`proptest`] strategies and a decoding harness for fuzzing.
+//!
+//! The strategies generate valid values of the types in this crate, for use in property
+//! tests of code built on top of them.  [`decode`] runs all decoders of this crate on
+//! arbitrary input and can be called from a fuzz target, it must never panic.
+//!
+//! ```
+//! use iroh_base::fuzz;
+//! use proptest::prelude::*;
+//!
+//! proptest!(|(addr in fuzz::node_addr())| {
+//!     let json = serde_json::to_string(&addr).unwrap();
+//!     let decoded: iroh_base::NodeAddr = serde_json::from_str(&json).unwrap();
+//!     prop_assert_eq!(addr, decoded);
+//! });
+//! ```
+
+use std::{
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+    str::FromStr,
+};
+
+use proptest::prelude::*;
+
+#[cfg(feature = "ticket")]
+use crate::ticket::{NodeTicket, Ticket};
+use crate::{NodeAddr, PublicKey, RelayUrl, SecretKey};
+
+/// Generates a [`SecretKey`].
+pub fn secret_key() -> impl Strategy<Value = SecretKey> {
+    prop::array::uniform32(any::<u8>()).prop_map(SecretKey::from)
+}
+
+/// Generates a [`PublicKey`].
+pub fn public_key() -> impl Strategy<Value = PublicKey> {
+    secret_key().prop_map(|key| key.public())
+}
+
+/// Generates a [`RelayUrl`].
+pub fn relay_url() -> impl Strategy<Value = RelayUrl> {
+    (
+        "[a-z][a-z0-9]{0,15}",
+        "[a-z]{2,6}",
+        prop::option::of(1u16..),
+    )
+        .prop_map(|(host, tld, port)| {
+            let url = match port {
+                Some(port) => format!("https://{host}.{tld}:{port}"),
+                None => format!("https://{host}.{tld}"),
+            };
+            RelayUrl::from_str(&url).expect("valid url")
+        })
+}
+
+/// Generates a [`SocketAddr`].
+pub fn socket_addr() -> impl Strategy<Value = SocketAddr> {
+    prop_oneof![
+        (any::<u32>(), any::<u16>()).prop_map(|(ip, port)| SocketAddrV4::new(
+            Ipv4Addr::from(ip),
+            port
+        )
+        .into()),
+        (any::<u128>(), any::<u16>()).prop_map(|(ip, port)| SocketAddrV6::new(
+            Ipv6Addr::from(ip),
+            port,
+            0,
+            0
+        )
+        .into()),
+    ]
+}
+
+/// Generates a [`NodeAddr`] with up to 8 direct addresses.
+pub fn node_addr() -> impl Strategy<Value = NodeAddr> {
+    (
+        public_key(),
+        prop::option::of(relay_url()),
+        prop::collection::btree_set(socket_addr(), 0..8),
+    )
+        .prop_map(|(node_id, relay_url, direct_addresses)| NodeAddr {
+            node_id,
+            relay_url,
+            direct_addresses,
+        })
+}
+
+/// Generates a [`NodeTicket`].
+#[cfg(feature = "ticket")]
+pub fn node_ticket() -> impl Strategy<Value = NodeTicket> {
+    node_addr().prop_map(NodeTicket::new)
+}
+
+/// Runs all decoders of this crate on `data`.
+///
+/// Decoding errors are ignored, this only checks that arbitrary input never makes a
+/// decoder panic.  Successfully decoded values are checked to round-trip.
+pub fn decode(data: &[u8]) {
+    if let Ok(bytes) = <[u8; 32]>::try_from(data) {
+        if let Ok(key) = PublicKey::from_bytes(&bytes) {
+            assert_eq!(key.as_bytes(), &bytes);
+        }
+    }
+    let Ok(s) = std::str::from_utf8(data) else {
+        #[cfg(feature = "ticket")]
+        decode_ticket_bytes(data);
+        return;
+    };
+    if let Ok(key) = PublicKey::from_str(s) {
+        assert_eq!(PublicKey::from_str(&key.to_string()).ok(), Some(key));
+    }
+    let _ = SecretKey::from_str(s);
+    if let Ok(url) = RelayUrl::from_str(s) {
+        assert_eq!(RelayUrl::from_str(&url.to_string()).ok(), Some(url));
+    }
+    #[cfg(feature = "ticket")]
+    {
+        if let Ok(ticket) = NodeTicket::from_str(s) {
+            assert_eq!(NodeTicket::from_str(&ticket.to_string()).ok(), Some(ticket));
+        }
+        decode_ticket_bytes(data);
+    }
+}
+
+#[cfg(feature = "ticket")]
+fn decode_ticket_bytes(data: &[u8]) {
+    if let Ok(ticket) = NodeTicket::from_bytes(data) {
+        assert_eq!(
+            NodeTicket::from_bytes(&ticket.to_bytes()).ok(),
+            Some(ticket)
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    proptest! {
+        #[test]
+        fn decode_arbitrary(data in prop::collection::vec(any::<u8>(), 0..256)) {
+            decode(&data);
+        }
+
+        #[test]
+        fn decode_arbitrary_str(s in "\\PC{0,128}") {
+            decode(s.as_bytes());
+        }
+
+        #[cfg(feature = "ticket")]
+        #[test]
+        fn decode_node_ticket(ticket in node_ticket()) {
+            decode(ticket.to_string().as_bytes());
+            decode(&ticket.to_bytes());
+        }
+    }
+}
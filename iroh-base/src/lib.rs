@@ -7,6 +7,8 @@
 #[cfg(feature = "ticket")]
 pub mod ticket;
 
+#[cfg(feature = "fuzz")]
+pub mod fuzz;
 #[cfg(feature = "key")]
 mod key;
 #[cfg(feature = "key")]
@@ -12,6 +12,8 @@ use snafu::{Backtrace, Snafu};
 use crate::{key::NodeId, relay_url::RelayUrl};
 
 mod node;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_vectors;
 
 pub use self::node::NodeTicket;
 
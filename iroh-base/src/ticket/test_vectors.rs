@@ -0,0 +1,126 @@
+//! Golden test vectors for the wire format of [`NodeTicket`].
+//!
+//! Other implementations of iroh tickets can check their encoders and decoders against
+//! these vectors.  Each vector is [`Serialize`], so the whole set can be exported, e.g. to
+//! JSON, for use in test suites written in other languages.  Requires the `test-utils`
+//! feature.
+//!
+//! The vectors are versioned with the ticket format: existing vectors never change, new
+//! vectors are added for new ticket variants.
+
+use std::str::FromStr;
+
+use serde::Serialize;
+
+use super::{NodeTicket, Ticket};
+use crate::{NodeAddr, PublicKey, RelayUrl};
+
+/// A [`NodeTicket`] and its expected encodings.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct NodeTicketVector {
+    /// A short description of the vector.
+    pub name: &'static str,
+    /// The node id, hex encoded.
+    pub node_id: &'static str,
+    /// The relay URL, if any.
+    pub relay_url: Option<&'static str>,
+    /// The direct addresses.
+    pub direct_addresses: &'static [&'static str],
+    /// The expected output of [`Ticket::to_bytes`], hex encoded.
+    pub bytes: &'static str,
+    /// The expected string encoding of the ticket.
+    pub ticket: &'static str,
+}
+
+const NODE_ID: &str = "ae58ff8833241ac82d6ff7611046ed67b5072d142c588d0063e942d9a75502b6";
+
+/// Test vectors for version 0 of the [`NodeTicket`] wire format.
+pub const NODE_TICKET_VECTORS: &[NodeTicketVector] = &[
+    NodeTicketVector {
+        name: "node id only",
+        node_id: NODE_ID,
+        relay_url: None,
+        direct_addresses: &[],
+        bytes: "00ae58ff8833241ac82d6ff7611046ed67b5072d142c588d0063e942d9a75502b60000",
+        ticket: "nodeacxfr74igmsbvsbnn73wcecg5vt3kbzncqwfrdiampuufwnhkublmaaa",
+    },
+    NodeTicketVector {
+        name: "relay url and ipv4 address",
+        node_id: NODE_ID,
+        relay_url: Some("http://derp.me./"),
+        direct_addresses: &["127.0.0.1:1024"],
+        bytes: "00ae58ff8833241ac82d6ff7611046ed67b5072d142c588d0063e942d9a75502b60110687474703a2f2f646572702e6d652e2f01007f0000018008",
+        ticket: "nodeacxfr74igmsbvsbnn73wcecg5vt3kbzncqwfrdiampuufwnhkublmaiqnb2hi4b2f4xwizlsoaxg2zjof4aqa7yaaaayaca",
+    },
+    NodeTicketVector {
+        name: "ipv4 and ipv6 addresses",
+        node_id: NODE_ID,
+        relay_url: None,
+        direct_addresses: &["192.168.1.5:4433", "[2001:db8::1]:4433"],
+        bytes: "00ae58ff8833241ac82d6ff7611046ed67b5072d142c588d0063e942d9a75502b6000200c0a80105d1220120010db8000000000000000000000001d122",
+        ticket: "nodeacxfr74igmsbvsbnn73wcecg5vt3kbzncqwfrdiampuufwnhkublmaacadakqaif2eraciabbw4aaaaaaaaaaaaaaaaaaaorei",
+    },
+    NodeTicketVector {
+        name: "relay url and mixed addresses",
+        node_id: NODE_ID,
+        relay_url: Some("https://euw1-1.relay.iroh.network./"),
+        direct_addresses: &["10.0.0.1:11204", "[fe80::1]:11204"],
+        bytes: "00ae58ff8833241ac82d6ff7611046ed67b5072d142c588d0063e942d9a75502b6012368747470733a2f2f657577312d312e72656c61792e69726f682e6e6574776f726b2e2f02000a000001c45701fe800000000000000000000000000001c457",
+        ticket: "nodeacxfr74igmsbvsbnn73wcecg5vt3kbzncqwfrdiampuufwnhkublmajdnb2hi4dthixs6zlvo4ys2mjoojswyylzfzuxe33ifzxgk5dxn5zgwlrpaiaauaaaahcfoap6qaaaaaaaaaaaaaaaaaaaaaabyrlq",
+    },
+];
+
+impl NodeTicketVector {
+    /// Returns the [`NodeAddr`] encoded in this vector.
+    pub fn node_addr(&self) -> NodeAddr {
+        let node_id = PublicKey::from_str(self.node_id).expect("valid vector");
+        let relay_url = self
+            .relay_url
+            .map(|url| RelayUrl::from_str(url).expect("valid vector"));
+        let addrs = self
+            .direct_addresses
+            .iter()
+            .map(|addr| addr.parse().expect("valid vector"));
+        NodeAddr::from_parts(node_id, relay_url, addrs)
+    }
+
+    /// Returns the [`NodeTicket`] encoded in this vector.
+    pub fn node_ticket(&self) -> NodeTicket {
+        NodeTicket::new(self.node_addr())
+    }
+
+    /// Returns the expected output of [`Ticket::to_bytes`].
+    pub fn bytes(&self) -> Vec<u8> {
+        data_encoding::HEXLOWER
+            .decode(self.bytes.as_bytes())
+            .expect("valid vector")
+    }
+
+    /// Checks that this crate encodes and decodes the vector as expected.
+    pub fn check(&self) -> bool {
+        let ticket = self.node_ticket();
+        ticket.to_bytes() == self.bytes()
+            && ticket.to_string() == self.ticket
+            && NodeTicket::from_bytes(&self.bytes()).ok().as_ref() == Some(&ticket)
+            && NodeTicket::from_str(self.ticket).ok().as_ref() == Some(&ticket)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_node_ticket_vectors() {
+        for vector in NODE_TICKET_VECTORS {
+            assert!(vector.check(), "vector {:?} failed", vector.name);
+        }
+    }
+
+    #[test]
+    fn test_node_ticket_vectors_json() {
+        let json = serde_json::to_value(NODE_TICKET_VECTORS).unwrap();
+        assert_eq!(json[1]["relay_url"], "http://derp.me./");
+        assert_eq!(json[1]["direct_addresses"][0], "127.0.0.1:1024");
+    }
+}
@@ -24,7 +24,10 @@ use url::Url;
 
 pub use self::conn::{ReceivedMessage, RecvError, SendError, SendMessage};
 #[cfg(not(wasm_browser))]
-use crate::dns::{DnsError, DnsResolver};
+use crate::{
+    dns::{DnsError, DnsResolver},
+    RelayTlsConfig, RelayTlsConfigError,
+};
 use crate::{
     http::{Protocol, RELAY_PATH},
     protos::relay::SendError as SendRelayError,
@@ -77,6 +80,9 @@ pub enum ConnectError {
     NoLocalAddr {},
     #[snafu(display("tls connection failed"))]
     Tls { source: std::io::Error },
+    #[cfg(not(wasm_browser))]
+    #[snafu(display("Invalid TLS configuration"))]
+    TlsConfig { source: RelayTlsConfigError },
     #[cfg(wasm_browser)]
     #[snafu(display("The relay protocol is not available in browsers"))]
     RelayProtoNotAvailable {},
@@ -132,6 +138,9 @@ pub struct ClientBuilder {
     insecure_skip_cert_verify: bool,
     /// HTTP Proxy
     proxy_url: Option<Url>,
+    /// How to verify the relay server's TLS certificates.
+    #[cfg(not(wasm_browser))]
+    tls_config: RelayTlsConfig,
     /// The secret key of this client.
     secret_key: SecretKey,
     /// The DNS resolver to use.
@@ -160,6 +169,8 @@ impl ClientBuilder {
             insecure_skip_cert_verify: false,
 
             proxy_url: None,
+            #[cfg(not(wasm_browser))]
+            tls_config: RelayTlsConfig::default(),
             secret_key,
             #[cfg(not(wasm_browser))]
             dns_resolver,
@@ -203,6 +214,15 @@ impl ClientBuilder {
         self
     }
 
+    /// Sets how to verify the relay server's TLS certificates.
+    ///
+    /// By default the certificates are verified using the web PKI roots.
+    #[cfg(not(wasm_browser))]
+    pub fn tls_config(mut self, config: RelayTlsConfig) -> Self {
+        self.tls_config = config;
+        self
+    }
+
     /// Set an explicit proxy url to proxy all HTTP(S) traffic through.
     pub fn proxy_url(mut self, url: Url) -> Self {
         self.proxy_url.replace(url);
@@ -41,6 +41,7 @@ pub struct MaybeTlsStreamBuilder {
     dns_resolver: DnsResolver,
     proxy_url: Option<Url>,
     prefer_ipv6: bool,
+    tls_config: RelayTlsConfig,
     #[cfg(any(test, feature = "test-utils"))]
     insecure_skip_cert_verify: bool,
 }
@@ -52,11 +53,17 @@ impl MaybeTlsStreamBuilder {
             dns_resolver,
             proxy_url: None,
             prefer_ipv6: false,
+            tls_config: RelayTlsConfig::default(),
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_cert_verify: false,
         }
     }
 
+    pub fn tls_config(mut self, config: RelayTlsConfig) -> Self {
+        self.tls_config = config;
+        self
+    }
+
     pub fn proxy_url(mut self, proxy_url: Option<Url>) -> Self {
         self.proxy_url = proxy_url;
         self
@@ -74,27 +81,15 @@ impl MaybeTlsStreamBuilder {
     }
 
     pub async fn connect(self) -> Result<MaybeTlsStream<ProxyStream>, ConnectError> {
-        let roots = rustls::RootCertStore {
-            roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
-        };
-        let mut config = rustls::client::ClientConfig::builder_with_provider(Arc::new(
-            rustls::crypto::ring::default_provider(),
-        ))
-        .with_safe_default_protocol_versions()
-        .expect("protocols supported by ring")
-        .with_root_certificates(roots)
-        .with_no_client_auth();
-        #[cfg(any(test, feature = "test-utils"))]
-        if self.insecure_skip_cert_verify {
-            warn!("Insecure config: SSL certificates from relay servers not verified");
-            config
-                .dangerous()
-                .set_certificate_verifier(Arc::new(NoCertVerifier));
-        }
-        config.resumption = Resumption::default();
-        let tls_connector: tokio_rustls::TlsConnector = Arc::new(config).into();
+        // The relay's TLS configuration does not apply to the proxy.
+        let proxy_tls_connector = self
+            .tls_connector(&RelayTlsConfig::default())
+            .context(TlsConfigSnafu)?;
+        let tls_connector = self
+            .tls_connector(&self.tls_config)
+            .context(TlsConfigSnafu)?;
 
-        let tcp_stream = self.dial_url(&tls_connector).await?;
+        let tcp_stream = self.dial_url(&proxy_tls_connector).await?;
 
         let local_addr = tcp_stream
             .local_addr()
@@ -121,6 +116,23 @@ impl MaybeTlsStreamBuilder {
         }
     }
 
+    fn tls_connector(
+        &self,
+        tls_config: &RelayTlsConfig,
+    ) -> Result<tokio_rustls::TlsConnector, RelayTlsConfigError> {
+        #[allow(unused_mut)]
+        let mut config = tls_config.client_config()?;
+        #[cfg(any(test, feature = "test-utils"))]
+        if self.insecure_skip_cert_verify {
+            warn!("Insecure config: SSL certificates from relay servers not verified");
+            config
+                .dangerous()
+                .set_certificate_verifier(Arc::new(NoCertVerifier));
+        }
+        config.resumption = Resumption::default();
+        Ok(Arc::new(config).into())
+    }
+
     fn use_tls(&self) -> bool {
         // only disable tls if we are explicitly dialing a http url
         #[allow(clippy::match_like_matches_macro)]
@@ -291,7 +303,8 @@ impl ClientBuilder {
         let mut builder =
             MaybeTlsStreamBuilder::new(self.url.clone().into(), self.dns_resolver.clone())
                 .prefer_ipv6(self.prefer_ipv6())
-                .proxy_url(self.proxy_url.clone());
+                .proxy_url(self.proxy_url.clone())
+                .tls_config(self.tls_config.clone());
 
         #[cfg(any(test, feature = "test-utils"))]
         if self.insecure_skip_cert_verify {
@@ -346,7 +359,8 @@ impl ClientBuilder {
         #[allow(unused_mut)]
         let mut builder = MaybeTlsStreamBuilder::new(dial_url.clone(), self.dns_resolver.clone())
             .prefer_ipv6(self.prefer_ipv6())
-            .proxy_url(self.proxy_url.clone());
+            .proxy_url(self.proxy_url.clone())
+            .tls_config(self.tls_config.clone());
 
         #[cfg(any(test, feature = "test-utils"))]
         if self.insecure_skip_cert_verify {
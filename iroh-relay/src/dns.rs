@@ -51,6 +51,10 @@ pub enum DnsError {
     },
     #[snafu(display("invalid DNS response: not a query for _iroh.z32encodedpubkey"))]
     InvalidResponse {},
+    #[snafu(display("unsupported nameserver URL scheme: {scheme}"))]
+    UnsupportedScheme { scheme: String },
+    #[snafu(display("no nameserver address"))]
+    NoNameserverAddrs {},
 }
 
 /// Error returned when an input value is too long for [`crate::node_info::UserData`].
@@ -115,13 +119,91 @@ impl DnsResolver {
 
     /// Create a new DNS resolver configured with a single UDP DNS nameserver.
     pub fn with_nameserver(nameserver: SocketAddr) -> Self {
-        let mut config = hickory_resolver::config::ResolverConfig::new();
         let nameserver_config = hickory_resolver::config::NameServerConfig::new(
             nameserver,
             hickory_resolver::proto::xfer::Protocol::Udp,
         );
+        Self::with_nameserver_config(nameserver_config)
+    }
+
+    /// Create a new DNS resolver configured with a single DNS-over-HTTPS nameserver.
+    ///
+    /// The `server_name` is used to verify the TLS certificate of the nameserver, queries
+    /// are sent to its `/dns-query` endpoint.  E.g. use `1.1.1.1:443` and
+    /// `cloudflare-dns.com` for Cloudflare's public resolver.
+    #[cfg(feature = "dns-over-https")]
+    pub fn with_https_nameserver(nameserver: SocketAddr, server_name: impl Into<String>) -> Self {
+        let mut nameserver_config = hickory_resolver::config::NameServerConfig::new(
+            nameserver,
+            hickory_resolver::proto::xfer::Protocol::Https,
+        );
+        nameserver_config.tls_dns_name = Some(server_name.into());
+        Self::with_nameserver_config(nameserver_config)
+    }
+
+    /// Create a new DNS resolver configured with the DNS-over-HTTPS nameserver at `url`.
+    ///
+    /// Queries are sent to the path of `url`, e.g. `https://cloudflare-dns.com/dns-query`, and
+    /// its host is used to verify the TLS certificate of the nameserver.  As the host can not
+    /// be resolved without a resolver, the nameserver is contacted at `addrs`, plus the host
+    /// itself if it is an IP address.
+    #[cfg(feature = "dns-over-https")]
+    pub fn with_https_url(
+        url: &Url,
+        addrs: impl IntoIterator<Item = IpAddr>,
+    ) -> Result<Self, DnsError> {
+        if url.scheme() != "https" {
+            return Err(UnsupportedSchemeSnafu {
+                scheme: url.scheme(),
+            }
+            .build());
+        }
+        let (server_name, host_addr) = match url.host().context(MissingHostSnafu)? {
+            url::Host::Domain(domain) => (domain.to_string(), None),
+            url::Host::Ipv4(ip) => (ip.to_string(), Some(IpAddr::V4(ip))),
+            url::Host::Ipv6(ip) => (ip.to_string(), Some(IpAddr::V6(ip))),
+        };
+        let port = url.port().unwrap_or(443);
+
+        let mut config = hickory_resolver::config::ResolverConfig::new();
+        for addr in host_addr.into_iter().chain(addrs) {
+            let mut nameserver_config = hickory_resolver::config::NameServerConfig::new(
+                SocketAddr::new(addr, port),
+                hickory_resolver::proto::xfer::Protocol::Https,
+            );
+            nameserver_config.tls_dns_name = Some(server_name.clone());
+            nameserver_config.http_endpoint = Some(url.path().to_string());
+            config.add_name_server(nameserver_config);
+        }
+        if config.name_servers().is_empty() {
+            return Err(NoNameserverAddrsSnafu.build());
+        }
+        Ok(Self::with_config(config))
+    }
+
+    /// Create a new DNS resolver configured with a single DNS-over-TLS nameserver.
+    ///
+    /// The `server_name` is used to verify the TLS certificate of the nameserver.  E.g. use
+    /// `1.1.1.1:853` and `cloudflare-dns.com` for Cloudflare's public resolver.
+    #[cfg(feature = "dns-over-tls")]
+    pub fn with_tls_nameserver(nameserver: SocketAddr, server_name: impl Into<String>) -> Self {
+        let mut nameserver_config = hickory_resolver::config::NameServerConfig::new(
+            nameserver,
+            hickory_resolver::proto::xfer::Protocol::Tls,
+        );
+        nameserver_config.tls_dns_name = Some(server_name.into());
+        Self::with_nameserver_config(nameserver_config)
+    }
+
+    fn with_nameserver_config(
+        nameserver_config: hickory_resolver::config::NameServerConfig,
+    ) -> Self {
+        let mut config = hickory_resolver::config::ResolverConfig::new();
         config.add_name_server(nameserver_config);
+        Self::with_config(config)
+    }
 
+    fn with_config(config: hickory_resolver::config::ResolverConfig) -> Self {
         let builder =
             TokioResolver::builder_with_config(config, TokioConnectionProvider::default());
         DnsResolver(builder.build())
@@ -485,4 +567,82 @@ pub(crate) mod tests {
         let result = stagger_call(f, &delays).await.unwrap();
         assert_eq!(result, 5)
     }
+
+    /// Returns the nameservers configured in `resolver`.
+    fn name_servers(resolver: &DnsResolver) -> &[hickory_resolver::config::NameServerConfig] {
+        resolver.0.config().name_servers()
+    }
+
+    #[test]
+    fn test_with_nameserver() {
+        let addr: SocketAddr = "127.0.0.1:5353".parse().unwrap();
+        let resolver = DnsResolver::with_nameserver(addr);
+        let [ns] = name_servers(&resolver) else {
+            panic!("expected a single nameserver");
+        };
+        assert_eq!(ns.socket_addr, addr);
+        assert_eq!(ns.protocol, hickory_resolver::proto::xfer::Protocol::Udp);
+    }
+
+    #[cfg(feature = "dns-over-tls")]
+    #[test]
+    fn test_with_tls_nameserver() {
+        let addr: SocketAddr = "1.1.1.1:853".parse().unwrap();
+        let resolver = DnsResolver::with_tls_nameserver(addr, "cloudflare-dns.com");
+        let [ns] = name_servers(&resolver) else {
+            panic!("expected a single nameserver");
+        };
+        assert_eq!(ns.socket_addr, addr);
+        assert_eq!(ns.protocol, hickory_resolver::proto::xfer::Protocol::Tls);
+        assert_eq!(ns.tls_dns_name.as_deref(), Some("cloudflare-dns.com"));
+    }
+
+    #[cfg(feature = "dns-over-https")]
+    #[test]
+    fn test_with_https_nameserver() {
+        let addr: SocketAddr = "1.1.1.1:443".parse().unwrap();
+        let resolver = DnsResolver::with_https_nameserver(addr, "cloudflare-dns.com");
+        let [ns] = name_servers(&resolver) else {
+            panic!("expected a single nameserver");
+        };
+        assert_eq!(ns.socket_addr, addr);
+        assert_eq!(ns.protocol, hickory_resolver::proto::xfer::Protocol::Https);
+        assert_eq!(ns.tls_dns_name.as_deref(), Some("cloudflare-dns.com"));
+    }
+
+    #[cfg(feature = "dns-over-https")]
+    #[test]
+    fn test_with_https_url() {
+        let url: Url = "https://cloudflare-dns.com:8443/custom-query"
+            .parse()
+            .unwrap();
+        let resolver = DnsResolver::with_https_url(&url, ["1.1.1.1".parse().unwrap()]).unwrap();
+        let [ns] = name_servers(&resolver) else {
+            panic!("expected a single nameserver");
+        };
+        assert_eq!(ns.socket_addr, "1.1.1.1:8443".parse().unwrap());
+        assert_eq!(ns.protocol, hickory_resolver::proto::xfer::Protocol::Https);
+        assert_eq!(ns.tls_dns_name.as_deref(), Some("cloudflare-dns.com"));
+        assert_eq!(ns.http_endpoint.as_deref(), Some("/custom-query"));
+
+        // An IP address host is used as nameserver address.
+        let url: Url = "https://1.1.1.1/dns-query".parse().unwrap();
+        let resolver = DnsResolver::with_https_url(&url, []).unwrap();
+        let [ns] = name_servers(&resolver) else {
+            panic!("expected a single nameserver");
+        };
+        assert_eq!(ns.socket_addr, "1.1.1.1:443".parse().unwrap());
+        assert_eq!(ns.tls_dns_name.as_deref(), Some("1.1.1.1"));
+
+        let url: Url = "https://cloudflare-dns.com/dns-query".parse().unwrap();
+        assert!(matches!(
+            DnsResolver::with_https_url(&url, []),
+            Err(DnsError::NoNameserverAddrs { .. })
+        ));
+        let url: Url = "http://1.1.1.1/dns-query".parse().unwrap();
+        assert!(matches!(
+            DnsResolver::with_https_url(&url, []),
+            Err(DnsError::UnsupportedScheme { .. })
+        ));
+    }
 }
@@ -52,5 +52,5 @@ pub use protos::relay::MAX_PACKET_SIZE;
 
 pub use self::{
     ping_tracker::PingTracker,
-    relay_map::{RelayMap, RelayNode, RelayQuicConfig},
+    relay_map::{RelayMap, RelayNode, RelayQuicConfig, RelayTlsConfig, RelayTlsConfigError},
 };
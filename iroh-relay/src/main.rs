@@ -708,6 +708,7 @@ async fn build_relay_config(cfg: Config) -> Result<relay::ServerConfig<std::io::
                 accept_conn_limit: limits.accept_conn_limit,
                 accept_conn_burst: limits.accept_conn_burst,
                 client_rx,
+                traffic_shaping: None,
             }
         }
         None => Default::default(),
@@ -3,7 +3,19 @@
 use std::{collections::BTreeMap, fmt, sync::Arc};
 
 use iroh_base::RelayUrl;
+use n0_snafu::SpanTrace;
+use nested_enum_utils::common_fields;
+use rustls::{
+    client::{
+        danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        WebPkiServerVerifier,
+    },
+    pki_types::{CertificateDer, ServerName, UnixTime},
+    DigitallySignedStruct, RootCertStore, SignatureScheme,
+};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use snafu::{Backtrace, ResultExt, Snafu};
 
 use crate::defaults::{DEFAULT_RELAY_QUIC_PORT, DEFAULT_STUN_PORT};
 
@@ -131,6 +143,9 @@ pub struct RelayNode {
     /// with this relay server.
     #[serde(default = "quic_config")]
     pub quic: Option<RelayQuicConfig>,
+    /// How to verify the TLS certificates of this relay server.
+    #[serde(default, skip_serializing_if = "RelayTlsConfig::is_default")]
+    pub tls: RelayTlsConfig,
 }
 
 impl From<RelayUrl> for RelayNode {
@@ -140,6 +155,7 @@ impl From<RelayUrl> for RelayNode {
             stun_only: false,
             stun_port: DEFAULT_STUN_PORT,
             quic: quic_config(),
+            tls: RelayTlsConfig::default(),
         }
     }
 }
@@ -164,6 +180,148 @@ impl Default for RelayQuicConfig {
     }
 }
 
+/// Configuration for verifying the TLS certificates of a relay server.
+///
+/// By default relay servers must present a certificate chaining up to one of the web PKI
+/// roots.  Self-hosted relays using a private certificate authority can instead list the
+/// roots to trust, and the accepted certificates can further be pinned by their public key.
+#[derive(Debug, Default, Deserialize, Serialize, Clone, Eq, PartialEq, PartialOrd, Ord)]
+pub struct RelayTlsConfig {
+    /// DER-encoded root certificates to trust instead of the web PKI roots.
+    #[serde(default)]
+    pub root_certs: Vec<Vec<u8>>,
+    /// SHA-256 hashes of the DER-encoded SubjectPublicKeyInfo of the accepted certificates.
+    ///
+    /// When not empty, the relay server's end-entity certificate must, in addition to
+    /// being valid, contain one of these public keys.
+    #[serde(default)]
+    pub spki_sha256_pins: Vec<[u8; 32]>,
+}
+
+/// Errors building the TLS configuration of a relay server.
+#[common_fields({
+    backtrace: Option<Backtrace>,
+    #[snafu(implicit)]
+    span_trace: SpanTrace,
+})]
+#[allow(missing_docs)]
+#[derive(Debug, Snafu)]
+#[non_exhaustive]
+pub enum RelayTlsConfigError {
+    #[snafu(display("Invalid root certificate"))]
+    InvalidRootCert { source: rustls::Error },
+    #[snafu(display("Failed to build certificate verifier"))]
+    Verifier {
+        source: rustls::client::VerifierBuilderError,
+    },
+}
+
+impl RelayTlsConfig {
+    /// Returns `true` if this is the default configuration, verifying using web PKI only.
+    pub fn is_default(&self) -> bool {
+        self.root_certs.is_empty() && self.spki_sha256_pins.is_empty()
+    }
+
+    /// Computes the pin for a DER-encoded SubjectPublicKeyInfo.
+    pub fn spki_sha256(spki: &[u8]) -> [u8; 32] {
+        Sha256::digest(spki).into()
+    }
+
+    /// Builds a [`rustls::ClientConfig`] verifying servers according to this configuration.
+    pub fn client_config(&self) -> Result<rustls::ClientConfig, RelayTlsConfigError> {
+        let provider = Arc::new(rustls::crypto::ring::default_provider());
+        let roots = if self.root_certs.is_empty() {
+            RootCertStore {
+                roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+            }
+        } else {
+            let mut roots = RootCertStore::empty();
+            for cert in &self.root_certs {
+                roots
+                    .add(CertificateDer::from(cert.as_slice()).into_owned())
+                    .context(InvalidRootCertSnafu)?;
+            }
+            roots
+        };
+        let builder = rustls::ClientConfig::builder_with_provider(provider.clone())
+            .with_safe_default_protocol_versions()
+            .expect("protocols supported by ring");
+        let config = if self.spki_sha256_pins.is_empty() {
+            builder.with_root_certificates(roots)
+        } else {
+            let inner = WebPkiServerVerifier::builder_with_provider(Arc::new(roots), provider)
+                .build()
+                .context(VerifierSnafu)?;
+            let verifier = SpkiPinningVerifier {
+                inner,
+                pins: self.spki_sha256_pins.clone(),
+            };
+            builder
+                .dangerous()
+                .with_custom_certificate_verifier(Arc::new(verifier))
+        };
+        Ok(config.with_no_client_auth())
+    }
+}
+
+/// Verifies the certificate chain and additionally requires a pinned public key.
+#[derive(Debug)]
+struct SpkiPinningVerifier {
+    inner: Arc<WebPkiServerVerifier>,
+    pins: Vec<[u8; 32]>,
+}
+
+impl ServerCertVerifier for SpkiPinningVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+        let cert = webpki::EndEntityCert::try_from(end_entity).map_err(|_| {
+            rustls::Error::InvalidCertificate(rustls::CertificateError::BadEncoding)
+        })?;
+        let pin = RelayTlsConfig::spki_sha256(&cert.subject_public_key_info());
+        if !self.pins.contains(&pin) {
+            return Err(rustls::Error::InvalidCertificate(
+                rustls::CertificateError::ApplicationVerificationFailure,
+            ));
+        }
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+}
+
 impl fmt::Display for RelayNode {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{}", self.url)
@@ -212,6 +212,8 @@ pub struct Limits {
     pub accept_conn_burst: Option<usize>,
     /// Rate limits for incoming traffic from a client connection.
     pub client_rx: Option<ClientRateLimit>,
+    /// Simulated network conditions for relayed packets, for testing.
+    pub traffic_shaping: Option<TrafficShaping>,
 }
 
 /// Per-client rate limit configuration.
@@ -223,6 +225,29 @@ pub struct ClientRateLimit {
     pub max_burst_bytes: Option<NonZeroU32>,
 }
 
+/// Simulated network conditions for relayed packets, for testing.
+///
+/// Every packet forwarded from one client to another is dropped with probability `loss`,
+/// otherwise it is delayed by `latency` plus a random duration of up to `jitter`.  Packets
+/// are delayed independently, so jitter can reorder them.  A bandwidth cap can be added
+/// using [`Limits::client_rx`].
+#[derive(Debug, Clone, Default)]
+pub struct TrafficShaping {
+    /// Delay added to every relayed packet.
+    pub latency: std::time::Duration,
+    /// Maximum random delay added on top of the `latency`.
+    pub jitter: std::time::Duration,
+    /// Probability of dropping a relayed packet, between `0.0` and `1.0`.
+    ///
+    /// Values outside this range are clamped to it, `NaN` is treated as `0.0`.
+    pub loss: f64,
+    /// Seed for the random decisions, to make them reproducible.
+    ///
+    /// The decisions for the packets of each client are derived from this seed and the
+    /// client's node id, independently of the other clients.
+    pub seed: u64,
+}
+
 /// TLS certificate configuration.
 #[derive(derive_more::Debug)]
 pub enum CertConfig<EC: fmt::Debug, EA: fmt::Debug = EC> {
@@ -416,6 +441,9 @@ impl Server {
                 if let Some(cfg) = relay_config.limits.client_rx {
                     builder = builder.client_rx_ratelimit(cfg);
                 }
+                if let Some(cfg) = relay_config.limits.traffic_shaping {
+                    builder = builder.traffic_shaping(cfg);
+                }
                 let http_addr = match relay_config.tls {
                     Some(tls_config) => {
                         let server_tls_config = match tls_config.cert {
@@ -886,22 +914,28 @@ mod tests {
     use tracing_test::traced_test;
 
     use super::{
-        Access, AccessConfig, RelayConfig, Server, ServerConfig, SpawnError, StunConfig,
-        NO_CONTENT_CHALLENGE_HEADER, NO_CONTENT_RESPONSE_HEADER,
+        Access, AccessConfig, Limits, RelayConfig, Server, ServerConfig, SpawnError, StunConfig,
+        TrafficShaping, NO_CONTENT_CHALLENGE_HEADER, NO_CONTENT_RESPONSE_HEADER,
     };
     use crate::{
         client::{conn::ReceivedMessage, ClientBuilder, SendMessage},
         dns::DnsResolver,
         http::{Protocol, HTTP_UPGRADE_PROTOCOL},
-        protos,
+        protos, RelayTlsConfig,
     };
 
     async fn spawn_local_relay() -> std::result::Result<Server, SpawnError> {
+        spawn_local_relay_with_limits(Default::default()).await
+    }
+
+    async fn spawn_local_relay_with_limits(
+        limits: Limits,
+    ) -> std::result::Result<Server, SpawnError> {
         Server::spawn(ServerConfig::<(), ()> {
             relay: Some(RelayConfig::<(), ()> {
                 http_bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
                 tls: None,
-                limits: Default::default(),
+                limits,
                 key_cache_capacity: Some(1024),
                 access: AccessConfig::Everyone,
             }),
@@ -1078,6 +1112,61 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    #[traced_test]
+    async fn test_relay_client_tls_config() -> Result<()> {
+        let server = Server::spawn(ServerConfig::<(), ()> {
+            relay: Some(RelayConfig::<(), ()> {
+                http_bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
+                tls: Some(super::testing::tls_config()),
+                limits: Default::default(),
+                key_cache_capacity: Some(1024),
+                access: AccessConfig::Everyone,
+            }),
+            quic: None,
+            stun: None,
+            metrics_addr: None,
+        })
+        .await?;
+        let relay_url: RelayUrl = format!("https://{}", server.https_addr().unwrap())
+            .parse()
+            .unwrap();
+        let cert = server.certificates().unwrap().remove(0);
+        let spki = webpki::EndEntityCert::try_from(&cert)
+            .unwrap()
+            .subject_public_key_info();
+        let pin = RelayTlsConfig::spki_sha256(&spki);
+
+        let connect = |tls_config: RelayTlsConfig| {
+            let secret_key = SecretKey::generate(rand::thread_rng());
+            let client = ClientBuilder::new(relay_url.clone(), secret_key, dns_resolver())
+                .tls_config(tls_config);
+            async move { client.connect().await }
+        };
+
+        // The self-signed certificate is not trusted by default.
+        assert!(connect(RelayTlsConfig::default()).await.is_err());
+
+        let trusted = RelayTlsConfig {
+            root_certs: vec![cert.to_vec()],
+            ..Default::default()
+        };
+        connect(trusted.clone()).await?;
+
+        let pinned = RelayTlsConfig {
+            spki_sha256_pins: vec![pin],
+            ..trusted.clone()
+        };
+        connect(pinned).await?;
+
+        let wrong_pin = RelayTlsConfig {
+            spki_sha256_pins: vec![[0u8; 32]],
+            ..trusted
+        };
+        assert!(connect(wrong_pin).await.is_err());
+        Ok(())
+    }
+
     #[tokio::test]
     #[traced_test]
     async fn test_relay_clients_both_websockets() -> Result<()> {
@@ -1355,4 +1444,85 @@ mod tests {
         }
         Ok(())
     }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_relay_traffic_shaping() -> Result<()> {
+        let latency = Duration::from_millis(300);
+        let server = spawn_local_relay_with_limits(Limits {
+            traffic_shaping: Some(TrafficShaping {
+                latency,
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .await?;
+        let relay_url: RelayUrl = format!("http://{}", server.http_addr().unwrap())
+            .parse()
+            .unwrap();
+        let resolver = dns_resolver();
+        let mut client_a = ClientBuilder::new(
+            relay_url.clone(),
+            SecretKey::generate(rand::thread_rng()),
+            resolver.clone(),
+        )
+        .connect()
+        .await?;
+        let b_secret_key = SecretKey::generate(rand::thread_rng());
+        let b_key = b_secret_key.public();
+        let mut client_b = ClientBuilder::new(relay_url, b_secret_key, resolver)
+            .connect()
+            .await?;
+
+        let start = std::time::Instant::now();
+        let msg = Bytes::from("hello, b");
+        let res = try_send_recv(&mut client_a, &mut client_b, b_key, msg.clone()).await?;
+        assert!(start.elapsed() >= latency);
+        let ReceivedMessage::ReceivedPacket { data, .. } = res else {
+            panic!("client_b received unexpected message {res:?}");
+        };
+        assert_eq!(msg, data);
+
+        // With full packet loss nothing arrives.
+        let server = spawn_local_relay_with_limits(Limits {
+            traffic_shaping: Some(TrafficShaping {
+                loss: 1.0,
+                ..Default::default()
+            }),
+            ..Default::default()
+        })
+        .await?;
+        let relay_url: RelayUrl = format!("http://{}", server.http_addr().unwrap())
+            .parse()
+            .unwrap();
+        let resolver = dns_resolver();
+        let mut client_a = ClientBuilder::new(
+            relay_url.clone(),
+            SecretKey::generate(rand::thread_rng()),
+            resolver.clone(),
+        )
+        .connect()
+        .await?;
+        let b_secret_key = SecretKey::generate(rand::thread_rng());
+        let b_key = b_secret_key.public();
+        let mut client_b = ClientBuilder::new(relay_url, b_secret_key, resolver)
+            .connect()
+            .await?;
+        for _ in 0..10 {
+            client_a
+                .send(SendMessage::SendPacket(b_key, msg.clone()))
+                .await?;
+        }
+        let res = tokio::time::timeout(Duration::from_millis(500), async {
+            loop {
+                match client_b.next().await {
+                    Some(Ok(ReceivedMessage::ReceivedPacket { .. })) | None => break,
+                    _ => continue,
+                }
+            }
+        })
+        .await;
+        assert!(res.is_err(), "packet was not dropped");
+        Ok(())
+    }
 }
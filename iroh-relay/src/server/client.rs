@@ -16,7 +16,7 @@ use tokio::{
     sync::mpsc::{self, error::TrySendError},
     time::MissedTickBehavior,
 };
-use tokio_util::{sync::CancellationToken, task::AbortOnDropHandle};
+use tokio_util::{sync::CancellationToken, task::AbortOnDropHandle, time::DelayQueue};
 use tracing::{debug, error, instrument, trace, warn, Instrument};
 
 use crate::{
@@ -28,7 +28,7 @@ use crate::{
         clients::Clients,
         metrics::Metrics,
         streams::{RelayedStream, StreamError},
-        ClientRateLimit,
+        ClientRateLimit, TrafficShaping,
     },
     PingTracker,
 };
@@ -50,6 +50,7 @@ pub(super) struct Config {
     pub(super) write_timeout: Duration,
     pub(super) channel_capacity: usize,
     pub(super) rate_limit: Option<ClientRateLimit>,
+    pub(super) traffic_shaping: Option<TrafficShaping>,
 }
 
 /// The [`Server`] side representation of a [`Client`]'s connection.
@@ -90,6 +91,7 @@ impl Client {
             write_timeout,
             channel_capacity,
             rate_limit,
+            traffic_shaping,
         } = config;
 
         let stream = match rate_limit {
@@ -121,6 +123,9 @@ impl Client {
             clients: clients.clone(),
             client_counter: ClientCounter::default(),
             ping_tracker: PingTracker::default(),
+            traffic_shaper: traffic_shaping.map(|config| TrafficShaper::new(config, node_id)),
+            delayed_packets: DelayQueue::new(),
+            delayed_packets_capacity: channel_capacity,
             metrics,
         };
 
@@ -314,6 +319,12 @@ struct Actor {
     /// Statistics about the connected clients
     client_counter: ClientCounter,
     ping_tracker: PingTracker,
+    /// Simulated network conditions for the packets sent by this client.
+    traffic_shaper: Option<TrafficShaper>,
+    /// Packets held back by the [`TrafficShaper`] until their delay has elapsed.
+    delayed_packets: DelayQueue<(NodeId, Bytes)>,
+    /// Maximum number of packets in `delayed_packets`, further packets are dropped.
+    delayed_packets_capacity: usize,
     metrics: Arc<Metrics>,
 }
 
@@ -368,6 +379,12 @@ impl Actor {
                     // reset the ping interval, we just received a message
                     ping_interval.reset();
                 }
+                Some(expired) = self.delayed_packets.next(), if !self.delayed_packets.is_empty() => {
+                    let (dst, data) = expired.into_inner();
+                    if let Err(err) = forward_packet(&self.clients, dst, data, self.node_id, &self.metrics) {
+                        warn!("failed to forward delayed packet: {err:#}");
+                    }
+                }
                 // First priority, disco packets
                 packet = self.disco_send_queue.recv() => {
                     let packet = packet.ok_or(DiscoSendQueuePacketDropSnafu.build())?;
@@ -493,17 +510,79 @@ impl Actor {
         Ok(())
     }
 
-    fn handle_frame_send_packet(&self, dst: NodeId, data: Bytes) -> Result<(), ForwardPacketError> {
-        if disco::looks_like_disco_wrapper(&data) {
-            self.metrics.disco_packets_recv.inc();
-            self.clients
-                .send_disco_packet(dst, data, self.node_id, &self.metrics)?;
-        } else {
-            self.metrics.send_packets_recv.inc();
-            self.clients
-                .send_packet(dst, data, self.node_id, &self.metrics)?;
+    fn handle_frame_send_packet(
+        &mut self,
+        dst: NodeId,
+        data: Bytes,
+    ) -> Result<(), ForwardPacketError> {
+        if let Some(ref mut shaper) = self.traffic_shaper {
+            let Some(delay) = shaper.delay() else {
+                trace!("traffic shaping: dropping packet");
+                return Ok(());
+            };
+            if self.delayed_packets.len() >= self.delayed_packets_capacity {
+                trace!("traffic shaping: too many delayed packets, dropping packet");
+                return Ok(());
+            }
+            self.delayed_packets.insert((dst, data), delay);
+            return Ok(());
         }
-        Ok(())
+        forward_packet(&self.clients, dst, data, self.node_id, &self.metrics)
+    }
+}
+
+/// Forwards a packet from `src` to the client `dst`.
+fn forward_packet(
+    clients: &Clients,
+    dst: NodeId,
+    data: Bytes,
+    src: NodeId,
+    metrics: &Metrics,
+) -> Result<(), ForwardPacketError> {
+    if disco::looks_like_disco_wrapper(&data) {
+        metrics.disco_packets_recv.inc();
+        clients.send_disco_packet(dst, data, src, metrics)?;
+    } else {
+        metrics.send_packets_recv.inc();
+        clients.send_packet(dst, data, src, metrics)?;
+    }
+    Ok(())
+}
+
+/// Applies the [`TrafficShaping`] configuration to the packets relayed from one client.
+#[derive(Debug)]
+struct TrafficShaper {
+    config: TrafficShaping,
+    rng: rand::rngs::StdRng,
+}
+
+impl TrafficShaper {
+    /// Creates the shaper for the client `node_id`.
+    ///
+    /// Each client gets its own random number generator, seeded from the configured seed
+    /// and its node id, so its decisions do not depend on the other clients.
+    fn new(mut config: TrafficShaping, node_id: NodeId) -> Self {
+        use rand::SeedableRng;
+        config.loss = match config.loss.is_nan() {
+            true => 0.0,
+            false => config.loss.clamp(0.0, 1.0),
+        };
+        let node_bits = u64::from_le_bytes(node_id.as_bytes()[..8].try_into().expect("8 bytes"));
+        let rng = rand::rngs::StdRng::seed_from_u64(config.seed ^ node_bits);
+        Self { config, rng }
+    }
+
+    /// Returns how long to delay the next packet, or `None` if it should be dropped.
+    fn delay(&mut self) -> Option<Duration> {
+        let rng = &mut self.rng;
+        if rng.gen_bool(self.config.loss) {
+            return None;
+        }
+        let jitter = match self.config.jitter.is_zero() {
+            true => Duration::ZERO,
+            false => rng.gen_range(Duration::ZERO..=self.config.jitter),
+        };
+        Some(self.config.latency + jitter)
     }
 }
 
@@ -791,6 +870,9 @@ mod tests {
             clients: clients.clone(),
             client_counter: ClientCounter::default(),
             ping_tracker: PingTracker::default(),
+            traffic_shaper: None,
+            delayed_packets: DelayQueue::new(),
+            delayed_packets_capacity: 10,
             metrics,
         };
 
@@ -883,6 +965,41 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_traffic_shaper_loss() {
+        for (loss, dropped) in [(f64::NAN, false), (-1.0, false), (2.0, true)] {
+            let node_id = SecretKey::generate(rand::thread_rng()).public();
+            let mut shaper = TrafficShaper::new(
+                TrafficShaping {
+                    loss,
+                    ..Default::default()
+                },
+                node_id,
+            );
+            assert_eq!(shaper.delay().is_none(), dropped, "loss {loss}");
+        }
+    }
+
+    #[test]
+    fn test_traffic_shaper_seed() {
+        let config = TrafficShaping {
+            jitter: Duration::from_millis(100),
+            loss: 0.5,
+            seed: 42,
+            ..Default::default()
+        };
+        let a = SecretKey::generate(rand::thread_rng()).public();
+        let b = SecretKey::generate(rand::thread_rng()).public();
+        let delays = |node_id| {
+            let mut shaper = TrafficShaper::new(config.clone(), node_id);
+            (0..64).map(|_| shaper.delay()).collect::<Vec<_>>()
+        };
+
+        // The decisions for a client only depend on the seed and its node id.
+        assert_eq!(delays(a), delays(a));
+        assert_ne!(delays(a), delays(b));
+    }
+
     #[tokio::test]
     #[traced_test]
     async fn test_rate_limit() -> Result {
@@ -216,6 +216,7 @@ mod tests {
                 write_timeout: Duration::from_secs(1),
                 channel_capacity: 10,
                 rate_limit: None,
+                traffic_shaping: None,
             },
             FramedRead::new(test_io, RelayCodec::test()),
         )
@@ -32,7 +32,7 @@ use crate::{
         client::Config,
         metrics::Metrics,
         streams::{MaybeTlsStream, RelayedStream},
-        BindTcpListenerSnafu, ClientRateLimit, NoLocalAddrSnafu,
+        BindTcpListenerSnafu, ClientRateLimit, NoLocalAddrSnafu, TrafficShaping,
     },
     KeyCache,
 };
@@ -279,6 +279,8 @@ pub(super) struct ServerBuilder {
     /// Rate-limiting is enforced on received traffic from individual clients.  This
     /// configuration applies to a single client connection.
     client_rx_ratelimit: Option<ClientRateLimit>,
+    /// Simulated network conditions for relayed packets.
+    traffic_shaping: Option<TrafficShaping>,
     /// The capacity of the key cache.
     key_cache_capacity: usize,
     /// Access config for nodes.
@@ -295,6 +297,7 @@ impl ServerBuilder {
             handlers: Default::default(),
             headers: HeaderMap::new(),
             client_rx_ratelimit: None,
+            traffic_shaping: None,
             key_cache_capacity: DEFAULT_KEY_CACHE_CAPACITY,
             access: AccessConfig::Everyone,
             metrics: None,
@@ -328,6 +331,12 @@ impl ServerBuilder {
         self
     }
 
+    /// Simulates network conditions for all relayed packets.
+    pub(super) fn traffic_shaping(mut self, config: TrafficShaping) -> Self {
+        self.traffic_shaping = Some(config);
+        self
+    }
+
     /// Adds a custom handler for a specific Method & URI.
     pub(super) fn request_handler(
         mut self,
@@ -363,6 +372,7 @@ impl ServerBuilder {
             self.handlers,
             self.headers,
             self.client_rx_ratelimit,
+            self.traffic_shaping,
             KeyCache::new(self.key_cache_capacity),
             self.access,
             self.metrics.unwrap_or_default(),
@@ -443,6 +453,7 @@ struct Inner {
     clients: Clients,
     write_timeout: Duration,
     rate_limit: Option<ClientRateLimit>,
+    traffic_shaping: Option<TrafficShaping>,
     key_cache: KeyCache,
     access: AccessConfig,
     metrics: Arc<Metrics>,
@@ -684,6 +695,7 @@ impl Inner {
             write_timeout: self.write_timeout,
             channel_capacity: PER_CLIENT_SEND_QUEUE_DEPTH,
             rate_limit: self.rate_limit,
+            traffic_shaping: self.traffic_shaping.clone(),
         };
         trace!("accept: create client");
         let node_id = client_conn_builder.node_id;
@@ -713,6 +725,7 @@ impl RelayService {
         handlers: Handlers,
         headers: HeaderMap,
         rate_limit: Option<ClientRateLimit>,
+        traffic_shaping: Option<TrafficShaping>,
         key_cache: KeyCache,
         access: AccessConfig,
         metrics: Arc<Metrics>,
@@ -723,6 +736,7 @@ impl RelayService {
             clients: Clients::default(),
             write_timeout: SERVER_WRITE_TIMEOUT,
             rate_limit,
+            traffic_shaping,
             key_cache,
             access,
             metrics,
@@ -1098,6 +1112,7 @@ mod tests {
             Default::default(),
             Default::default(),
             None,
+            None,
             KeyCache::test(),
             AccessConfig::Everyone,
             Default::default(),
@@ -1186,6 +1201,7 @@ mod tests {
             Default::default(),
             Default::default(),
             None,
+            None,
             KeyCache::test(),
             AccessConfig::Everyone,
             Default::default(),
@@ -0,0 +1,124 @@
+//! Example of a bi-directional message protocol built on [`iroh::proto_util`].
+//!
+//! Both sides exchange discrete messages over a single long-lived bi-directional stream.
+//! The accepting side answers every message it receives, while sending heartbeats whenever
+//! it has been quiet for a while.  The connecting side gracefully closes the message stream
+//! once it is done, which the accepting side observes as the end of the conversation.
+//!
+//! ## Usage
+//!
+//!     cargo run --example chat --features=examples
+
+use iroh::{
+    endpoint::Connection,
+    proto_util::{self, DEFAULT_HEARTBEAT_INTERVAL, DEFAULT_IDLE_TIMEOUT},
+    protocol::{AcceptError, ProtocolHandler, Router},
+    Endpoint, NodeAddr,
+};
+use n0_snafu::{Result, ResultExt};
+use n0_watcher::Watcher as _;
+
+/// Each protocol is identified by its ALPN string.
+const ALPN: &[u8] = b"iroh-example/chat/0";
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let router = start_accept_side().await?;
+    let node_addr = router.endpoint().node_addr().initialized().await?;
+
+    connect_side(node_addr).await?;
+
+    // This makes sure the endpoint in the router is closed properly and connections close gracefully
+    router.shutdown().await.e()?;
+
+    Ok(())
+}
+
+async fn connect_side(addr: NodeAddr) -> Result<()> {
+    let endpoint = Endpoint::builder().discovery_n0().bind().await?;
+    let conn = endpoint.connect(addr, ALPN).await?;
+
+    // Wrap a single bi-directional stream into a message sender and receiver.
+    let (send, recv) = conn.open_bi().await.e()?;
+    let (mut sender, receiver) = proto_util::framed(send, recv);
+    // The other side sends heartbeats, so we can detect it going away.
+    let mut receiver = receiver.idle_timeout(DEFAULT_IDLE_TIMEOUT);
+
+    for line in ["Hello!", "How are you?", "Bye!"] {
+        println!("> {line}");
+        sender.send(line.as_bytes()).await?;
+        let Some(reply) = receiver.recv().await? else {
+            println!("the other side closed the conversation");
+            break;
+        };
+        println!("< {}", String::from_utf8_lossy(&reply));
+    }
+
+    // Tell the other side we are done talking, and wait for it to do the same.
+    sender.close().await?;
+    while let Some(reply) = receiver.recv().await? {
+        println!("< {}", String::from_utf8_lossy(&reply));
+    }
+
+    conn.close(0u32.into(), b"bye!");
+    endpoint.close().await;
+    Ok(())
+}
+
+async fn start_accept_side() -> Result<Router> {
+    let endpoint = Endpoint::builder().discovery_n0().bind().await?;
+    let router = Router::builder(endpoint).accept(ALPN, Chat).spawn();
+    Ok(router)
+}
+
+#[derive(Debug, Clone)]
+struct Chat;
+
+impl ProtocolHandler for Chat {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        let node_id = connection.remote_node_id()?;
+        println!("accepted connection from {node_id}");
+
+        let (send, recv) = connection.accept_bi().await?;
+        let (mut sender, mut receiver) = proto_util::framed(send, recv);
+
+        // Receiving is not cancel-safe, so it runs in its own task and hands complete
+        // messages over to the loop below.
+        let (msg_tx, mut msg_rx) = tokio::sync::mpsc::channel(16);
+        let recv_task = tokio::spawn(async move {
+            while let Some(msg) = receiver.recv().await? {
+                if msg_tx.send(msg).await.is_err() {
+                    break;
+                }
+            }
+            Ok::<_, proto_util::RecvError>(())
+        });
+
+        let mut heartbeat = tokio::time::interval(DEFAULT_HEARTBEAT_INTERVAL);
+        loop {
+            tokio::select! {
+                msg = msg_rx.recv() => {
+                    let Some(msg) = msg else {
+                        // The other side closed the message stream.
+                        break;
+                    };
+                    let reply = format!("you said {:?}", String::from_utf8_lossy(&msg));
+                    sender.send(reply.as_bytes()).await.map_err(AcceptError::from_err)?;
+                    heartbeat.reset();
+                }
+                _ = heartbeat.tick() => {
+                    sender.heartbeat().await.map_err(AcceptError::from_err)?;
+                }
+            }
+        }
+        recv_task
+            .await
+            .map_err(AcceptError::from_err)?
+            .map_err(AcceptError::from_err)?;
+        sender.close().await.map_err(AcceptError::from_err)?;
+
+        // Wait until the remote closes the connection.
+        connection.closed().await;
+        Ok(())
+    }
+}
@@ -23,7 +23,7 @@ pub const DEFAULT_METRICS_PORT: u16 = 9090;
 
 /// Production configuration.
 pub mod prod {
-    use iroh_relay::{RelayMap, RelayNode, RelayQuicConfig};
+    use iroh_relay::{RelayMap, RelayNode, RelayQuicConfig, RelayTlsConfig};
 
     use super::*;
 
@@ -54,6 +54,7 @@ pub mod prod {
             stun_only: false,
             stun_port: DEFAULT_STUN_PORT,
             quic: Some(RelayQuicConfig::default()),
+            tls: RelayTlsConfig::default(),
         }
     }
 
@@ -68,6 +69,7 @@ pub mod prod {
             stun_only: false,
             stun_port: DEFAULT_STUN_PORT,
             quic: Some(RelayQuicConfig::default()),
+            tls: RelayTlsConfig::default(),
         }
     }
 
@@ -82,6 +84,7 @@ pub mod prod {
             stun_only: false,
             stun_port: DEFAULT_STUN_PORT,
             quic: Some(RelayQuicConfig::default()),
+            tls: RelayTlsConfig::default(),
         }
     }
 }
@@ -92,7 +95,7 @@ pub mod prod {
 ///
 /// Note: we have staging servers in EU and NA, but no corresponding staging server for AP at this time.
 pub mod staging {
-    use iroh_relay::{RelayMap, RelayNode, RelayQuicConfig};
+    use iroh_relay::{RelayMap, RelayNode, RelayQuicConfig, RelayTlsConfig};
 
     use super::*;
 
@@ -117,6 +120,7 @@ pub mod staging {
             stun_only: false,
             stun_port: DEFAULT_STUN_PORT,
             quic: Some(RelayQuicConfig::default()),
+            tls: RelayTlsConfig::default(),
         }
     }
 
@@ -131,6 +135,7 @@ pub mod staging {
             stun_only: false,
             stun_port: DEFAULT_STUN_PORT,
             quic: Some(RelayQuicConfig::default()),
+            tls: RelayTlsConfig::default(),
         }
     }
 }
@@ -12,8 +12,8 @@
 //! [module docs]: crate
 
 use std::{
-    any::Any,
-    collections::BTreeSet,
+    any::{Any, TypeId},
+    collections::{BTreeSet, HashMap},
     future::{Future, IntoFuture},
     net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6},
     pin::Pin,
@@ -22,18 +22,21 @@ use std::{
 };
 
 use ed25519_dalek::{pkcs8::DecodePublicKey, VerifyingKey};
-use iroh_base::{NodeAddr, NodeId, RelayUrl, SecretKey};
+use iroh_base::{NodeAddr, NodeId, PublicKey, RelayUrl, SecretKey};
 use iroh_relay::RelayMap;
 use n0_future::{time::Duration, Stream};
 use n0_watcher::Watcher;
 use nested_enum_utils::common_fields;
 use pin_project::pin_project;
 use snafu::{ensure, ResultExt, Snafu};
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, instrument, trace, warn};
 use url::Url;
 
 #[cfg(wasm_browser)]
 use crate::discovery::pkarr::PkarrResolver;
+#[cfg(all(not(wasm_browser), feature = "dns-over-https"))]
+use crate::dns::DnsError;
 #[cfg(not(wasm_browser))]
 use crate::{discovery::dns::DnsDiscovery, dns::DnsResolver};
 use crate::{
@@ -47,6 +50,7 @@ use crate::{
     tls, RelayProtocol,
 };
 
+mod remote_relay_map;
 mod rtt_actor;
 
 // Missing still: SendDatagram and ConnectionClose::frame_type's Type.
@@ -63,13 +67,19 @@ pub use quinn_proto::{
         AeadKey, CryptoError, ExportKeyingMaterialError, HandshakeTokenKey,
         ServerConfig as CryptoServerConfig, UnsupportedVersion,
     },
-    FrameStats, PathStats, TransportError, TransportErrorCode, UdpStats, Written,
+    FrameStats, PathStats, Side, TransportError, TransportErrorCode, UdpStats, Written,
 };
 
-use self::rtt_actor::RttMessage;
+pub use self::remote_relay_map::{
+    RelayMapManifest, RemoteRelayMapError, SignedRelayMap, REMOTE_RELAY_MAP_REFRESH_INTERVAL,
+};
+use self::{
+    remote_relay_map::{FetchOptions, RemoteRelayMap},
+    rtt_actor::RttMessage,
+};
 pub use super::magicsock::{
     AddNodeAddrError, ConnectionType, ControlMsg, DirectAddr, DirectAddrInfo, DirectAddrType,
-    RemoteInfo, Source,
+    IpStack, NetworkChange, RemoteInfo, Source,
 };
 
 /// The delay to fall back to discovery when direct addresses fail.
@@ -103,6 +113,7 @@ pub enum PathSelection {
 pub struct Builder {
     secret_key: Option<SecretKey>,
     relay_mode: RelayMode,
+    remote_relay_map: Option<(Url, PublicKey)>,
     relay_protocol: iroh_relay::http::Protocol,
     alpn_protocols: Vec<Vec<u8>>,
     transport_config: quinn::TransportConfig,
@@ -119,6 +130,7 @@ pub struct Builder {
     insecure_skip_relay_cert_verify: bool,
     addr_v4: Option<SocketAddrV4>,
     addr_v6: Option<SocketAddrV6>,
+    ip_stack: IpStack,
     #[cfg(any(test, feature = "test-utils"))]
     path_selection: PathSelection,
 }
@@ -130,6 +142,7 @@ impl Default for Builder {
         Self {
             secret_key: Default::default(),
             relay_mode: default_relay_mode(),
+            remote_relay_map: None,
             relay_protocol: iroh_relay::http::Protocol::default(),
             alpn_protocols: Default::default(),
             transport_config,
@@ -144,6 +157,7 @@ impl Default for Builder {
             insecure_skip_relay_cert_verify: false,
             addr_v4: None,
             addr_v6: None,
+            ip_stack: IpStack::default(),
             #[cfg(any(test, feature = "test-utils"))]
             path_selection: PathSelection::default(),
         }
@@ -158,6 +172,13 @@ impl Builder {
 
     /// Binds the magic endpoint.
     pub async fn bind(self) -> Result<Endpoint, BindError> {
+        ensure!(
+            !(self.ip_stack == IpStack::Ipv4Only && self.addr_v6.is_some())
+                && !(self.ip_stack == IpStack::Ipv6Only && self.addr_v4.is_some()),
+            IpStackMismatchSnafu {
+                ip_stack: self.ip_stack,
+            }
+        );
         let relay_map = self.relay_mode.relay_map();
         let secret_key = self
             .secret_key
@@ -186,15 +207,16 @@ impl Builder {
         let msock_opts = magicsock::Options {
             addr_v4: self.addr_v4,
             addr_v6: self.addr_v6,
+            ip_stack: self.ip_stack,
             secret_key,
             relay_map,
             relay_protocol: self.relay_protocol,
             node_map: self.node_map,
             discovery,
             discovery_user_data: self.discovery_user_data,
-            proxy_url: self.proxy_url,
+            proxy_url: self.proxy_url.clone(),
             #[cfg(not(wasm_browser))]
-            dns_resolver,
+            dns_resolver: dns_resolver.clone(),
             server_config,
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_relay_cert_verify: self.insecure_skip_relay_cert_verify,
@@ -202,7 +224,18 @@ impl Builder {
             path_selection: self.path_selection,
             metrics,
         };
-        Endpoint::bind(static_config, msock_opts).await
+        let mut ep = Endpoint::bind(static_config, msock_opts).await?;
+        if let Some((url, public_key)) = self.remote_relay_map {
+            let opts = FetchOptions {
+                #[cfg(not(wasm_browser))]
+                dns_resolver,
+                #[cfg(not(wasm_browser))]
+                proxy_url: self.proxy_url,
+            };
+            let remote = RemoteRelayMap::spawn(ep.msock.clone(), url, public_key, opts);
+            ep.remote_relay_map = Some(Arc::new(remote));
+        }
+        Ok(ep)
     }
 
     // # The very common methods everyone basically needs.
@@ -229,6 +262,20 @@ impl Builder {
         self
     }
 
+    /// Sets which IP address families to bind sockets for.
+    ///
+    /// By default both an IPv4 and an IPv6 socket are bound, see [`IpStack::DualStack`].
+    /// Use [`IpStack::Ipv4Only`] on hosts with broken IPv6 routing.  The bound addresses
+    /// are returned by [`Endpoint::bound_sockets`].
+    ///
+    /// Setting a bind address for a family the stack does not use, e.g. combining
+    /// [`IpStack::Ipv4Only`] with [`Builder::bind_addr_v6`], makes [`Builder::bind`] fail
+    /// with [`BindError::IpStackMismatch`].
+    pub fn ip_stack(mut self, ip_stack: IpStack) -> Self {
+        self.ip_stack = ip_stack;
+        self
+    }
+
     /// Sets a secret key to authenticate with other peers.
     ///
     /// This secret key's public key will be the [`PublicKey`] of this endpoint and thus
@@ -275,6 +322,19 @@ impl Builder {
         self
     }
 
+    /// Fetches the relay map from a [`SignedRelayMap`] published at `url`.
+    ///
+    /// The endpoint starts with the relay map of its [`RelayMode`], use
+    /// [`RelayMode::Disabled`] to start without relay servers.  When binding it fetches the
+    /// relay map, retrying with a backoff until this succeeded, and then again every
+    /// [`REMOTE_RELAY_MAP_REFRESH_INTERVAL`].  Only unexpired manifests signed by
+    /// `public_key` and with a higher version than the current one are used.  Use
+    /// [`Endpoint::ready`] to wait for the first fetched relay map.
+    pub fn remote_relay_map(mut self, url: Url, public_key: PublicKey) -> Self {
+        self.remote_relay_map = Some((url, public_key));
+        self
+    }
+
     /// Sets the protocol to use for relay connections.
     ///
     /// Options are either [`RelayProtocol::Websocket`] or [`RelayProtocol::Relay`].
@@ -458,12 +518,36 @@ impl Builder {
     /// host system's DNS configuration. You can pass a custom instance of [`DnsResolver`]
     /// here to use a differently configured DNS resolver for this endpoint, or to share
     /// a [`DnsResolver`] between multiple endpoints.
+    ///
+    /// With the `dns-over-https` feature, `DnsResolver::with_https_nameserver` and
+    /// `DnsResolver::with_https_url` create resolvers which encrypt all lookups, see also
+    /// [`Builder::dns_over_https`].  With the `dns-over-tls` feature,
+    /// `DnsResolver::with_tls_nameserver` does the same using DNS-over-TLS.
     #[cfg(not(wasm_browser))]
     pub fn dns_resolver(mut self, dns_resolver: DnsResolver) -> Self {
         self.dns_resolver = Some(dns_resolver);
         self
     }
 
+    /// Resolves all DNS lookups of this endpoint using the DNS-over-HTTPS nameserver at `url`.
+    ///
+    /// This keeps the lookups, e.g. of relay hostnames and of node addresses, private from
+    /// the local network.  As the host of `url` can not be resolved without a resolver, the
+    /// nameserver is contacted at `addrs`, plus the host itself if it is an IP address.  E.g.
+    /// use `https://cloudflare-dns.com/dns-query` with `1.1.1.1` and `1.0.0.1`.
+    ///
+    /// Fails if `url` is not an HTTPS URL, or if no nameserver address is known.  This
+    /// replaces any resolver set with [`Builder::dns_resolver`].
+    #[cfg(all(not(wasm_browser), feature = "dns-over-https"))]
+    pub fn dns_over_https(
+        self,
+        url: &Url,
+        addrs: impl IntoIterator<Item = IpAddr>,
+    ) -> Result<Self, DnsError> {
+        let dns_resolver = DnsResolver::with_https_url(url, addrs)?;
+        Ok(self.dns_resolver(dns_resolver))
+    }
+
     /// Sets an explicit proxy url to proxy all HTTP(S) traffic through.
     pub fn proxy_url(mut self, url: Url) -> Self {
         self.proxy_url.replace(url);
@@ -565,6 +649,10 @@ pub struct Endpoint {
     rtt_actor: Arc<rtt_actor::RttHandle>,
     /// Configuration structs for quinn, holds the transport config, certificate setup, secret key etc.
     static_config: Arc<StaticConfig>,
+    /// Task fetching the relay map, see [`Builder::remote_relay_map`].
+    remote_relay_map: Option<Arc<RemoteRelayMap>>,
+    /// Cancelled when the endpoint is closed, to stop tasks tied to the endpoint.
+    cancel_token: CancellationToken,
 }
 
 #[allow(missing_docs)]
@@ -620,6 +708,8 @@ pub enum BindError {
     MagicSpawn {
         source: magicsock::CreateHandleError,
     },
+    #[snafu(display("bind address configured for an IP family not used by {ip_stack:?}"))]
+    IpStackMismatch { ip_stack: IpStack },
 }
 
 #[allow(missing_docs)]
@@ -670,6 +760,8 @@ impl Endpoint {
             msock,
             rtt_actor: Arc::new(rtt_actor::RttHandle::new(metrics)),
             static_config: Arc::new(static_config),
+            remote_relay_map: None,
+            cancel_token: CancellationToken::new(),
         };
         Ok(ep)
     }
@@ -814,6 +906,7 @@ impl Endpoint {
         Ok(Connecting {
             inner: connect,
             ep: self.clone(),
+            side: Side::Client,
             remote_node_id: Some(node_id),
             _discovery_drop_guard,
         })
@@ -1050,6 +1143,45 @@ impl Endpoint {
         self.msock.direct_addresses()
     }
 
+    /// Waits until the endpoint can be reached by other nodes.
+    ///
+    /// The endpoint is ready once it discovered its [direct addresses] and, unless no relay
+    /// servers are configured, connected to its [home relay].  With
+    /// [`Builder::remote_relay_map`] the relay map is fetched first.  When compiled to Wasm only the home relay is waited
+    /// for.
+    ///
+    /// This waits indefinitely if no home relay can be reached, e.g. without network
+    /// connectivity.  Use a timeout to decide whether to carry on without it:
+    ///
+    /// ```no_run
+    /// # use std::time::Duration;
+    /// # use iroh::Endpoint;
+    /// # async fn wrapper() -> n0_snafu::Result {
+    /// let endpoint = Endpoint::builder().bind().await?;
+    /// if tokio::time::timeout(Duration::from_secs(5), endpoint.ready())
+    ///     .await
+    ///     .is_err()
+    /// {
+    ///     println!("endpoint is not ready yet, continuing anyway");
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [direct addresses]: Endpoint::direct_addresses
+    /// [home relay]: Endpoint::home_relay
+    pub async fn ready(&self) -> Result<(), n0_watcher::Disconnected> {
+        #[cfg(not(wasm_browser))]
+        self.direct_addresses().initialized().await?;
+        if let Some(remote) = &self.remote_relay_map {
+            remote.initialized().await?;
+        }
+        if !self.relay_map().is_empty() {
+            self.home_relay().initialized().await?;
+        }
+        Ok(())
+    }
+
     /// Returns a [`Watcher`] for any net-reports run from this [`Endpoint`].
     ///
     /// A `net-report` checks the network conditions of the [`Endpoint`], such as
@@ -1197,11 +1329,47 @@ impl Endpoint {
 
     /// Returns the discovery mechanism, if configured.
     ///
-    /// See [`Builder::discovery`].
+    /// The returned reference keeps delegating to the current discovery mechanism after it
+    /// was replaced using [`Endpoint::set_discovery`].  See [`Builder::discovery`].
     pub fn discovery(&self) -> Option<&dyn Discovery> {
         self.msock.discovery()
     }
 
+    /// Returns the discovery mechanism currently in use, if any.
+    ///
+    /// Unlike [`Endpoint::discovery`] this returns the service itself, which is not
+    /// affected by later calls to [`Endpoint::set_discovery`].
+    pub fn current_discovery(&self) -> Option<Arc<dyn Discovery>> {
+        self.msock.current_discovery()
+    }
+
+    /// Replaces the discovery mechanism of the running endpoint.
+    ///
+    /// Our address is published to the new discovery service right away, and new lookups
+    /// use it.  Lookups which are already running and existing connections are not
+    /// affected.  Use `None` to disable discovery.
+    ///
+    /// See [`Builder::discovery`] to set the discovery mechanism when binding.
+    pub async fn set_discovery(&self, discovery: Option<Box<dyn Discovery>>) {
+        self.msock.set_discovery(discovery).await
+    }
+
+    /// Returns the relay servers used by this endpoint.
+    ///
+    /// See [`Builder::relay_mode`] and [`Endpoint::set_relay_map`].
+    pub fn relay_map(&self) -> RelayMap {
+        self.msock.relay_map()
+    }
+
+    /// Replaces the relay servers used by the running endpoint.
+    ///
+    /// This triggers a new net report, which selects the home relay from the new
+    /// [`RelayMap`].  Connections are not dropped, they keep using their current paths
+    /// until the endpoint moved to the new home relay.
+    pub fn set_relay_map(&self, relay_map: RelayMap) {
+        self.msock.set_relay_map(relay_map)
+    }
+
     /// Returns metrics collected for this endpoint.
     ///
     /// The endpoint internally collects various metrics about its operation.
@@ -1337,6 +1505,20 @@ impl Endpoint {
         self.msock.network_change().await;
     }
 
+    /// Returns a stream of the network changes handled by this endpoint.
+    ///
+    /// Connections are migrated to the new network automatically, this stream allows
+    /// applications to observe when this happens, e.g. to refresh application state which
+    /// depends on the network.  A [`NetworkChange`] is yielded once the endpoint finished
+    /// processing the change.  To follow how an individual connection recovers, use
+    /// [`Endpoint::conn_type`].
+    ///
+    /// The stream should be processed in a loop. If the stream is not processed fast enough,
+    /// [`Lagged`] may be yielded, indicating that items were missed.
+    pub fn network_changes(&self) -> impl Stream<Item = Result<NetworkChange, Lagged>> {
+        self.msock.network_changes()
+    }
+
     // # Methods to update internal state.
 
     /// Sets the initial user-defined data to be published in discovery services for this node.
@@ -1390,6 +1572,10 @@ impl Endpoint {
         }
 
         tracing::debug!("Connections closed");
+        self.cancel_token.cancel();
+        if let Some(remote) = &self.remote_relay_map {
+            remote.stop();
+        }
         self.msock.close().await;
     }
 
@@ -1398,6 +1584,12 @@ impl Endpoint {
         self.msock.is_closed()
     }
 
+    /// Returns a token which is cancelled when the endpoint is closed.
+    #[cfg(feature = "metrics")]
+    pub(crate) fn cancel_token(&self) -> &CancellationToken {
+        &self.cancel_token
+    }
+
     // # Remaining private methods
 
     /// Return the quic mapped address for this `node_id` and possibly start discovery
@@ -1573,6 +1765,7 @@ impl Incoming {
         self.inner.accept().map(|conn| Connecting {
             inner: conn,
             ep: self.ep,
+            side: Side::Server,
             remote_node_id: None,
             _discovery_drop_guard: None,
         })
@@ -1592,6 +1785,7 @@ impl Incoming {
             .map(|conn| Connecting {
                 inner: conn,
                 ep: self.ep,
+                side: Side::Server,
                 remote_node_id: None,
                 _discovery_drop_guard: None,
             })
@@ -1624,10 +1818,21 @@ impl Incoming {
     }
 
     /// Returns the peer's UDP address.
+    ///
+    /// For iroh nodes this is the address the QUIC layer uses for the node, which is the
+    /// same for all paths to the node.  See [`Incoming::remote_ip`] for the IP address the
+    /// connection attempt was actually received from.
     pub fn remote_address(&self) -> SocketAddr {
         self.inner.remote_address()
     }
 
+    /// Returns the IP address the connection attempt was received from.
+    ///
+    /// Returns `None` if the connection attempt was received via a relay server.
+    pub fn remote_ip(&self) -> Option<IpAddr> {
+        self.ep.msock.remote_ip(self.inner.remote_address())
+    }
+
     /// Whether the socket address that is initiating this connection has been validated.
     ///
     /// This means that the sender of the initial packet has proved that they can receive
@@ -1667,7 +1872,7 @@ impl Future for IncomingFuture {
             Poll::Pending => Poll::Pending,
             Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
             Poll::Ready(Ok(inner)) => {
-                let conn = Connection { inner };
+                let conn = Connection::new(inner, Side::Server);
                 try_send_rtt_msg(&conn, this.ep, None);
                 Poll::Ready(Ok(conn))
             }
@@ -1682,6 +1887,8 @@ pub struct Connecting {
     #[pin]
     inner: quinn::Connecting,
     ep: Endpoint,
+    /// Whether we initiated or accepted the connection.
+    side: Side,
     remote_node_id: Option<NodeId>,
     /// We run discovery as long as we haven't established a connection yet.
     #[debug("Option<DiscoveryTask>")]
@@ -1755,7 +1962,7 @@ impl Connecting {
     pub fn into_0rtt(self) -> Result<(Connection, ZeroRttAccepted), Self> {
         match self.inner.into_0rtt() {
             Ok((inner, zrtt_accepted)) => {
-                let conn = Connection { inner };
+                let conn = Connection::new(inner, self.side);
                 let zrtt_accepted = ZeroRttAccepted {
                     inner: zrtt_accepted,
                     _discovery_drop_guard: self._discovery_drop_guard,
@@ -1772,6 +1979,7 @@ impl Connecting {
             Err(inner) => Err(Self {
                 inner,
                 ep: self.ep,
+                side: self.side,
                 remote_node_id: self.remote_node_id,
                 _discovery_drop_guard: self._discovery_drop_guard,
             }),
@@ -1805,7 +2013,7 @@ impl Future for Connecting {
             Poll::Pending => Poll::Pending,
             Poll::Ready(Err(err)) => Poll::Ready(Err(err)),
             Poll::Ready(Ok(inner)) => {
-                let conn = Connection { inner };
+                let conn = Connection::new(inner, *this.side);
                 try_send_rtt_msg(&conn, this.ep, *this.remote_node_id);
                 Poll::Ready(Ok(conn))
             }
@@ -1854,6 +2062,10 @@ impl Future for ZeroRttAccepted {
 #[derive(Debug, Clone)]
 pub struct Connection {
     inner: quinn::Connection,
+    /// Whether this endpoint initiated or accepted the connection.
+    side: Side,
+    /// Values attached with [`Connection::set_context`], shared by all clones.
+    context: Arc<std::sync::Mutex<HashMap<TypeId, Arc<dyn Any + Send + Sync>>>>,
 }
 
 #[allow(missing_docs)]
@@ -1864,6 +2076,14 @@ pub struct RemoteNodeIdError {
 }
 
 impl Connection {
+    fn new(inner: quinn::Connection, side: Side) -> Self {
+        Self {
+            inner,
+            side,
+            context: Default::default(),
+        }
+    }
+
     /// Initiates a new outgoing unidirectional stream.
     ///
     /// Streams are cheap and instantaneous to open unless blocked by flow control. As a
@@ -2117,6 +2337,39 @@ impl Connection {
         self.inner.stable_id()
     }
 
+    /// Whether this endpoint initiated ([`Side::Client`]) or accepted ([`Side::Server`]) the
+    /// connection.
+    #[inline]
+    pub fn side(&self) -> Side {
+        self.side
+    }
+
+    /// Attaches a value to this connection, retrievable with [`Connection::context`].
+    ///
+    /// This allows passing information gathered while accepting a connection, e.g. the
+    /// user authenticated by an [`AccessLimit`], on to the protocol handler.  At most one
+    /// value per type is kept, setting a value of the same type again replaces it.  The
+    /// value is shared by all clones of this connection.
+    ///
+    /// [`AccessLimit`]: crate::protocol::AccessLimit
+    pub fn set_context<T: Send + Sync + 'static>(&self, value: T) {
+        self.context
+            .lock()
+            .expect("poisoned")
+            .insert(TypeId::of::<T>(), Arc::new(value));
+    }
+
+    /// Returns the value of type `T` attached with [`Connection::set_context`].
+    pub fn context<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        let value = self
+            .context
+            .lock()
+            .expect("poisoned")
+            .get(&TypeId::of::<T>())?
+            .clone();
+        value.downcast().ok()
+    }
+
     /// Derives keying material from this connection's TLS session secrets.
     ///
     /// When both peers call this method with the same `label` and `context`
@@ -2283,7 +2536,7 @@ fn is_cgi() -> bool {
 #[cfg(test)]
 mod tests {
     use std::{
-        net::SocketAddr,
+        net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
         time::{Duration, Instant},
     };
 
@@ -2299,7 +2552,11 @@ mod tests {
 
     use super::Endpoint;
     use crate::{
-        endpoint::{ConnectOptions, Connection, ConnectionType, RemoteInfo},
+        discovery::static_provider::StaticProvider,
+        endpoint::{
+            BindError, ConnectOptions, Connection, ConnectionType, IpStack, RelayMapManifest,
+            RemoteInfo, SignedRelayMap,
+        },
         test_utils::{run_relay_server, run_relay_server_with},
         RelayMode,
     };
@@ -2831,6 +3088,279 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    #[traced_test]
+    async fn test_set_relay_map() -> Result {
+        let (relay_map, relay_url, _guard) = run_relay_server().await?;
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .insecure_skip_relay_cert_verify(true)
+            .bind()
+            .await?;
+        assert!(ep.relay_map().is_empty());
+
+        ep.set_relay_map(relay_map.clone());
+        assert_eq!(ep.relay_map(), relay_map);
+        let home_relay =
+            tokio::time::timeout(Duration::from_secs(10), ep.home_relay().initialized())
+                .await
+                .e()??;
+        assert_eq!(home_relay, relay_url);
+
+        // Without relay servers there is no home relay.
+        ep.set_relay_map(crate::RelayMap::empty());
+        tokio::time::timeout(Duration::from_secs(10), async {
+            let mut home_relay = ep.home_relay();
+            while !home_relay.get().e()?.is_empty() {
+                home_relay.updated().await.e()?;
+            }
+            Ok::<_, Error>(())
+        })
+        .await
+        .e()??;
+
+        ep.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_ready() -> Result {
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        tokio::time::timeout(Duration::from_secs(10), ep.ready())
+            .await
+            .e()?
+            .e()?;
+        assert!(ep.direct_addresses().get().e()?.is_some());
+        ep.close().await;
+
+        let (relay_map, relay_url, _guard) = run_relay_server().await?;
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Custom(relay_map))
+            .insecure_skip_relay_cert_verify(true)
+            .bind()
+            .await?;
+        tokio::time::timeout(Duration::from_secs(10), ep.ready())
+            .await
+            .e()?
+            .e()?;
+        assert_eq!(ep.home_relay().get().e()?, vec![relay_url]);
+        ep.close().await;
+        Ok(())
+    }
+
+    #[cfg(feature = "dns-over-https")]
+    #[tokio::test]
+    async fn test_dns_over_https() -> Result {
+        let url: Url = "https://cloudflare-dns.com/dns-query".parse().e()?;
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .dns_over_https(&url, ["1.1.1.1".parse().e()?])?
+            .bind()
+            .await?;
+        ep.close().await;
+
+        // Without an address the nameserver can not be reached.
+        assert!(Endpoint::builder().dns_over_https(&url, []).is_err());
+        let url: Url = "http://1.1.1.1/dns-query".parse().e()?;
+        assert!(Endpoint::builder().dns_over_https(&url, []).is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_relay_mode_remote() -> Result {
+        let (relay_map, relay_url, _guard) = run_relay_server().await?;
+        let publisher = SecretKey::generate(rand::thread_rng());
+        let manifest = RelayMapManifest::new(
+            1,
+            relay_map.nodes().map(|node| (**node).clone()).collect(),
+            Duration::from_secs(60),
+        );
+        let signed = SignedRelayMap::sign(&manifest, &publisher);
+
+        // The first request fails, the endpoint has to retry.
+        let requests = std::sync::Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let app = axum::Router::new().route(
+            "/relays.json",
+            axum::routing::get({
+                let requests = requests.clone();
+                move || async move {
+                    use axum::response::IntoResponse;
+                    match requests.fetch_add(1, std::sync::atomic::Ordering::SeqCst) {
+                        0 => axum::http::StatusCode::SERVICE_UNAVAILABLE.into_response(),
+                        _ => axum::Json(signed).into_response(),
+                    }
+                }
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.e()?;
+        let url = url::Url::parse(&format!(
+            "http://{}/relays.json",
+            listener.local_addr().e()?
+        ))
+        .e()?;
+        let _server = AbortOnDropHandle::new(tokio::spawn(async move {
+            axum::serve(listener, app).await.ok();
+        }));
+
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .remote_relay_map(url, publisher.public())
+            .insecure_skip_relay_cert_verify(true)
+            .bind()
+            .await?;
+        tokio::time::timeout(Duration::from_secs(10), ep.ready())
+            .await
+            .e()?
+            .e()?;
+        assert!(requests.load(std::sync::atomic::Ordering::SeqCst) >= 2);
+        assert_eq!(ep.home_relay().get().e()?, vec![relay_url]);
+        assert_eq!(ep.relay_map(), relay_map);
+
+        ep.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_incoming_remote_ip() -> Result {
+        let ep1 = Endpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let ep2 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let addr1 = ep1.node_addr().initialized().await?;
+        let ep2_ips: Vec<IpAddr> = ep2
+            .direct_addresses()
+            .initialized()
+            .await?
+            .into_iter()
+            .map(|addr| addr.addr.ip())
+            .collect();
+
+        let connect = tokio::spawn({
+            let ep2 = ep2.clone();
+            async move { ep2.connect(addr1, TEST_ALPN).await }
+        });
+        let incoming = ep1.accept().await.e()?;
+        // The QUIC layer only sees the mapped address of the node.
+        assert!(!ep2_ips.contains(&incoming.remote_address().ip()));
+        let remote_ip = incoming.remote_ip().e()?;
+        assert!(ep2_ips.contains(&remote_ip));
+        let _conn = incoming.await.e()?;
+        connect.await.e()??;
+
+        ep1.close().await;
+        ep2.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_set_discovery() -> Result {
+        let ep1 = Endpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let ep2 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        assert!(ep2.discovery().is_none());
+        assert!(ep2.connect(ep1.node_id(), TEST_ALPN).await.is_err());
+
+        let addr1 = ep1.node_addr().initialized().await?;
+        let discovery = StaticProvider::from_node_info([addr1]);
+        ep2.set_discovery(Some(Box::new(discovery))).await;
+        assert!(ep2.discovery().is_some());
+        assert!(ep2.current_discovery().is_some());
+
+        let accept = tokio::spawn({
+            let ep1 = ep1.clone();
+            async move {
+                let conn = ep1.accept().await.e()?.await.e()?;
+                conn.closed().await;
+                Ok::<_, Error>(())
+            }
+        });
+        let conn = ep2.connect(ep1.node_id(), TEST_ALPN).await?;
+        conn.close(0u32.into(), b"done");
+        accept.await.e()??;
+
+        ep1.close().await;
+        ep2.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    #[traced_test]
+    async fn test_ip_stack_ipv4_only() -> Result {
+        let ep1 = Endpoint::builder()
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .ip_stack(IpStack::Ipv4Only)
+            .bind()
+            .await?;
+        let bound = ep1.bound_sockets();
+        assert_eq!(bound.len(), 1);
+        assert!(bound[0].is_ipv4());
+
+        let ep2 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .ip_stack(IpStack::Ipv4Only)
+            .bind()
+            .await?;
+        let addr1 = ep1.node_addr().initialized().await?;
+        assert!(addr1.direct_addresses().all(|addr| addr.is_ipv4()));
+
+        let accept = tokio::spawn({
+            let ep1 = ep1.clone();
+            async move {
+                let conn = ep1.accept().await.e()?.await.e()?;
+                conn.closed().await;
+                Ok::<_, Error>(())
+            }
+        });
+        let conn = ep2.connect(addr1, TEST_ALPN).await?;
+        conn.close(0u32.into(), b"done");
+        accept.await.e()??;
+
+        ep1.close().await;
+        ep2.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_ip_stack_ipv4_only_rejects_v6_addr() {
+        let res = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .ip_stack(IpStack::Ipv4Only)
+            .bind_addr_v6(SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, 0, 0, 0))
+            .bind()
+            .await;
+        assert!(matches!(res, Err(BindError::IpStackMismatch { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_ip_stack_ipv6_only_rejects_v4_addr() {
+        let res = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .ip_stack(IpStack::Ipv6Only)
+            .bind_addr_v4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0))
+            .bind()
+            .await;
+        assert!(matches!(res, Err(BindError::IpStackMismatch { .. })));
+    }
+
     async fn spawn_0rtt_server(secret_key: SecretKey, log_span: tracing::Span) -> Result<Endpoint> {
         let server = Endpoint::builder()
             .secret_key(secret_key)
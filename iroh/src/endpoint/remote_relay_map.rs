@@ -0,0 +1,405 @@
+//! Relay maps fetched from a signed manifest, see [`Builder::remote_relay_map`].
+//!
+//! [`Builder::remote_relay_map`]: super::Builder::remote_relay_map
+
+#[cfg(not(wasm_browser))]
+use std::net::SocketAddr;
+
+use backon::{Backoff, BackoffBuilder, ExponentialBuilder};
+use iroh_base::{PublicKey, SecretKey, Signature, SignatureError};
+#[cfg(not(wasm_browser))]
+use iroh_relay::dns::{DnsError, DnsResolver};
+use iroh_relay::{RelayMap, RelayNode};
+use n0_future::{
+    task::{self, AbortOnDropHandle},
+    time::{self, Duration, SystemTime},
+    StreamExt,
+};
+use n0_watcher::{Watchable, Watcher};
+use nested_enum_utils::common_fields;
+use serde::{Deserialize, Serialize};
+use snafu::{ResultExt, Snafu};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info_span, warn, Instrument};
+use url::Url;
+
+use crate::magicsock::Handle;
+
+/// How often the relay map set with [`Builder::remote_relay_map`] is fetched.
+///
+/// Until the first manifest was fetched successfully, failed fetches are instead retried
+/// with an exponential backoff.
+///
+/// [`Builder::remote_relay_map`]: super::Builder::remote_relay_map
+pub const REMOTE_RELAY_MAP_REFRESH_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// The timeout for a single fetch of a [`SignedRelayMap`].
+#[cfg(not(wasm_browser))]
+const FETCH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The maximum size of a [`SignedRelayMap`] in bytes.
+///
+/// Larger responses are rejected before their signature is checked.
+const MAX_MANIFEST_SIZE: usize = 1024 * 1024;
+
+/// The timeout for resolving the host of the manifest URL.
+#[cfg(not(wasm_browser))]
+const DNS_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// A versioned list of relay servers, published as a [`SignedRelayMap`].
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RelayMapManifest {
+    /// The version of this manifest.
+    ///
+    /// An endpoint only replaces its relay map with a manifest of a higher version than the
+    /// one it currently uses, which protects against rolling back to an older manifest.
+    pub version: u64,
+    /// When this manifest expires, in seconds since the UNIX epoch.
+    ///
+    /// Expired manifests are rejected, which bounds how long an old manifest can be replayed
+    /// to an endpoint which does not yet know a newer version, e.g. after a restart.
+    pub expires_at: u64,
+    /// The relay servers.
+    pub nodes: Vec<RelayNode>,
+}
+
+impl RelayMapManifest {
+    /// Creates a manifest which expires `valid_for` from now.
+    pub fn new(version: u64, nodes: Vec<RelayNode>, valid_for: Duration) -> Self {
+        let expires_at = (SystemTime::now() + valid_for)
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        Self {
+            version,
+            expires_at,
+            nodes,
+        }
+    }
+
+    /// Returns the [`RelayMap`] of the relay servers in this manifest.
+    pub fn relay_map(&self) -> RelayMap {
+        self.nodes.iter().cloned().collect()
+    }
+
+    /// Returns whether this manifest has expired.
+    pub fn is_expired(&self) -> bool {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default();
+        now >= self.expires_at
+    }
+}
+
+/// A [`RelayMapManifest`] signed by its publisher.
+///
+/// This is the JSON document fetched from the URL given to [`Builder::remote_relay_map`].
+///
+/// [`Builder::remote_relay_map`]: super::Builder::remote_relay_map
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignedRelayMap {
+    /// The JSON encoded [`RelayMapManifest`].
+    manifest: String,
+    /// The signature of `manifest`.
+    signature: Signature,
+}
+
+impl SignedRelayMap {
+    /// Signs a manifest with the secret key of its publisher.
+    pub fn sign(manifest: &RelayMapManifest, secret_key: &SecretKey) -> Self {
+        let manifest = serde_json::to_string(manifest).expect("valid json");
+        let signature = secret_key.sign(manifest.as_bytes());
+        Self {
+            manifest,
+            signature,
+        }
+    }
+
+    /// Verifies the signature of the publisher and returns the manifest.
+    ///
+    /// Fails if the manifest has expired.
+    pub fn verify(&self, public_key: &PublicKey) -> Result<RelayMapManifest, RemoteRelayMapError> {
+        public_key
+            .verify(self.manifest.as_bytes(), &self.signature)
+            .context(InvalidSignatureSnafu)?;
+        let manifest: RelayMapManifest =
+            serde_json::from_str(&self.manifest).context(DecodeSnafu)?;
+        snafu::ensure!(
+            !manifest.is_expired(),
+            ExpiredSnafu {
+                expires_at: manifest.expires_at
+            }
+        );
+        Ok(manifest)
+    }
+}
+
+/// Errors fetching a [`SignedRelayMap`].
+#[common_fields({
+    backtrace: Option<snafu::Backtrace>,
+    #[snafu(implicit)]
+    span_trace: n0_snafu::SpanTrace,
+})]
+#[allow(missing_docs)]
+#[derive(Debug, Snafu)]
+#[non_exhaustive]
+pub enum RemoteRelayMapError {
+    #[snafu(display("Creating HTTP client failed"))]
+    CreateClient { source: reqwest::Error },
+    #[cfg(not(wasm_browser))]
+    #[snafu(display("Failed to resolve the relay map host"))]
+    Dns { source: DnsError },
+    #[snafu(display("Failed to fetch the relay map"))]
+    Fetch { source: reqwest::Error },
+    #[snafu(display("The relay map is larger than {max} bytes"))]
+    TooLarge { max: usize },
+    #[snafu(display("Failed to decode the relay map"))]
+    Decode { source: serde_json::Error },
+    #[snafu(display("Invalid relay map signature"))]
+    InvalidSignature { source: SignatureError },
+    #[snafu(display("The relay map expired at {expires_at}"))]
+    Expired { expires_at: u64 },
+}
+
+/// How a [`RemoteRelayMap`] connects to the server hosting the manifest.
+#[derive(Debug, Clone)]
+pub(super) struct FetchOptions {
+    /// The resolver for the host of the manifest URL.
+    #[cfg(not(wasm_browser))]
+    pub(super) dns_resolver: DnsResolver,
+    /// The proxy to fetch the manifest through.
+    #[cfg(not(wasm_browser))]
+    pub(super) proxy_url: Option<Url>,
+}
+
+/// Builds the HTTP client fetching the manifest at `url`.
+async fn build_client(
+    url: &Url,
+    opts: &FetchOptions,
+) -> Result<reqwest::Client, RemoteRelayMapError> {
+    #[allow(unused_mut)]
+    let mut builder = reqwest::ClientBuilder::new();
+
+    #[cfg(not(wasm_browser))]
+    {
+        builder = builder.timeout(FETCH_TIMEOUT);
+        if let Some(proxy_url) = &opts.proxy_url {
+            let proxy = reqwest::Proxy::all(proxy_url.clone()).context(CreateClientSnafu)?;
+            builder = builder.proxy(proxy);
+        }
+        if let Some(url::Host::Domain(domain)) = url.host() {
+            // Use our own resolver rather than getaddrinfo.
+            let addrs: Vec<_> = opts
+                .dns_resolver
+                .lookup_ipv4_ipv6(domain, DNS_TIMEOUT)
+                .await
+                .context(DnsSnafu)?
+                .map(|ip| SocketAddr::new(ip, 0))
+                .collect();
+            builder = builder.resolve_to_addrs(domain, &addrs);
+        }
+    }
+    #[cfg(wasm_browser)]
+    let _ = (url, opts);
+
+    builder.build().context(CreateClientSnafu)
+}
+
+/// Fetches and verifies a [`SignedRelayMap`].
+async fn fetch(
+    url: &Url,
+    public_key: &PublicKey,
+    opts: &FetchOptions,
+) -> Result<RelayMapManifest, RemoteRelayMapError> {
+    let client = build_client(url, opts).await?;
+    let response = client
+        .get(url.clone())
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .context(FetchSnafu)?;
+    snafu::ensure!(
+        response
+            .content_length()
+            .is_none_or(|len| len <= MAX_MANIFEST_SIZE as u64),
+        TooLargeSnafu {
+            max: MAX_MANIFEST_SIZE
+        }
+    );
+    let mut body = Vec::new();
+    let mut stream = response.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk.context(FetchSnafu)?;
+        snafu::ensure!(
+            body.len() + chunk.len() <= MAX_MANIFEST_SIZE,
+            TooLargeSnafu {
+                max: MAX_MANIFEST_SIZE
+            }
+        );
+        body.extend_from_slice(&chunk);
+    }
+    let signed: SignedRelayMap = serde_json::from_slice(&body).context(DecodeSnafu)?;
+    signed.verify(public_key)
+}
+
+/// A task updating the relay map of the magicsock from the manifest at a URL.
+#[derive(Debug)]
+pub(super) struct RemoteRelayMap {
+    /// The version of the current manifest, `None` until the first one was fetched.
+    version: Watchable<Option<u64>>,
+    cancel: CancellationToken,
+    _task: AbortOnDropHandle<()>,
+}
+
+impl RemoteRelayMap {
+    /// Spawns the task fetching the manifest at `url`, signed by `public_key`.
+    pub(super) fn spawn(
+        msock: Handle,
+        url: Url,
+        public_key: PublicKey,
+        opts: FetchOptions,
+    ) -> Self {
+        let version = Watchable::new(None);
+        let cancel = CancellationToken::new();
+        let span = info_span!("remote-relay-map", %url);
+        let task = task::spawn({
+            let version = version.clone();
+            let cancel = cancel.clone();
+            async move {
+                tokio::select! {
+                    biased;
+                    _ = cancel.cancelled() => debug!("stopped"),
+                    _ = refresh(&msock, &url, &public_key, &opts, &version) => {}
+                }
+            }
+            .instrument(span)
+        });
+        Self {
+            version,
+            cancel,
+            _task: AbortOnDropHandle::new(task),
+        }
+    }
+
+    /// Waits until the first manifest was fetched and applied.
+    pub(super) async fn initialized(&self) -> Result<u64, n0_watcher::Disconnected> {
+        self.version.watch().initialized().await
+    }
+
+    /// Stops refreshing the relay map.
+    pub(super) fn stop(&self) {
+        self.cancel.cancel();
+    }
+}
+
+/// Fetches the manifest at `url` forever, applying newer versions to the magicsock.
+async fn refresh(
+    msock: &Handle,
+    url: &Url,
+    public_key: &PublicKey,
+    opts: &FetchOptions,
+    version: &Watchable<Option<u64>>,
+) {
+    let mut backoff = build_backoff();
+    loop {
+        let current = version.get();
+        match fetch(url, public_key, opts).await {
+            Ok(manifest) if current.is_none_or(|v| manifest.version > v) => {
+                debug!(version = manifest.version, "updating relay map");
+                msock.set_relay_map(manifest.relay_map());
+                version.set(Some(manifest.version)).ok();
+            }
+            Ok(manifest) => {
+                debug!(version = manifest.version, "ignoring relay map, not newer");
+            }
+            Err(err) => warn!("failed to fetch relay map: {err:#}"),
+        }
+        let delay = match version.get() {
+            Some(_) => REMOTE_RELAY_MAP_REFRESH_INTERVAL,
+            None => backoff.next().unwrap_or(REMOTE_RELAY_MAP_REFRESH_INTERVAL),
+        };
+        time::sleep(delay).await;
+    }
+}
+
+fn build_backoff() -> impl Backoff {
+    ExponentialBuilder::new()
+        .with_min_delay(Duration::from_millis(100))
+        .with_max_delay(Duration::from_secs(60))
+        .with_jitter()
+        .without_max_times()
+        .build()
+}
+
+#[cfg(test)]
+mod tests {
+    use iroh_base::{RelayUrl, SecretKey};
+
+    use super::*;
+
+    #[test]
+    fn test_signed_relay_map() {
+        let secret_key = SecretKey::generate(rand::thread_rng());
+        let url: RelayUrl = "https://relay.example.com".parse().unwrap();
+        let manifest = RelayMapManifest::new(
+            3,
+            vec![RelayNode::from(url.clone())],
+            Duration::from_secs(60),
+        );
+
+        let signed = SignedRelayMap::sign(&manifest, &secret_key);
+        let json = serde_json::to_string(&signed).unwrap();
+        let signed: SignedRelayMap = serde_json::from_str(&json).unwrap();
+        assert_eq!(signed.verify(&secret_key.public()).unwrap(), manifest);
+        assert!(manifest.relay_map().contains_node(&url));
+
+        let other_key = SecretKey::generate(rand::thread_rng());
+        assert!(matches!(
+            signed.verify(&other_key.public()),
+            Err(RemoteRelayMapError::InvalidSignature { .. })
+        ));
+
+        let tampered = SignedRelayMap {
+            manifest: signed.manifest.replace("\"version\":3", "\"version\":4"),
+            signature: signed.signature,
+        };
+        assert!(tampered.verify(&secret_key.public()).is_err());
+
+        let expired = RelayMapManifest {
+            expires_at: 0,
+            ..manifest
+        };
+        let signed = SignedRelayMap::sign(&expired, &secret_key);
+        assert!(matches!(
+            signed.verify(&secret_key.public()),
+            Err(RemoteRelayMapError::Expired { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_too_large() {
+        let body = vec![b' '; MAX_MANIFEST_SIZE + 1];
+        let app = axum::Router::new().route(
+            "/relays.json",
+            axum::routing::get(move || async move { body }),
+        );
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url: Url = format!("http://{}/relays.json", listener.local_addr().unwrap())
+            .parse()
+            .unwrap();
+        let _server = AbortOnDropHandle::new(tokio::spawn(async move {
+            axum::serve(listener, app).await.ok();
+        }));
+
+        let opts = FetchOptions {
+            dns_resolver: DnsResolver::new(),
+            proxy_url: None,
+        };
+        let public_key = SecretKey::generate(rand::thread_rng()).public();
+        assert!(matches!(
+            fetch(&url, &public_key, &opts).await,
+            Err(RemoteRelayMapError::TooLarge { .. })
+        ));
+    }
+}
@@ -268,7 +268,11 @@ pub mod dns;
 pub mod endpoint;
 pub mod metrics;
 pub mod net_report;
+pub mod prelude;
+pub mod proto_util;
 pub mod protocol;
+#[cfg(feature = "metrics")]
+pub mod telemetry;
 
 pub use endpoint::{Endpoint, RelayMode};
 pub use iroh_base::{
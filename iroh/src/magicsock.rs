@@ -36,7 +36,7 @@ use n0_future::{
     boxed::BoxStream,
     task::{self, JoinSet},
     time::{self, Duration, Instant},
-    StreamExt,
+    Stream, StreamExt, TryStreamExt,
 };
 use n0_watcher::{self, Watchable, Watcher};
 use nested_enum_utils::common_fields;
@@ -71,7 +71,9 @@ use crate::net_report::{IpMappedAddr, QuicConfig};
 use crate::{
     defaults::timeouts::NET_REPORT_TIMEOUT,
     disco::{self, SendAddr},
-    discovery::{Discovery, DiscoveryItem, DiscoverySubscribers, NodeData, UserData},
+    discovery::{
+        Discovery, DiscoveryError, DiscoveryItem, DiscoverySubscribers, Lagged, NodeData, UserData,
+    },
     key::{public_ed_box, secret_ed_box, DecryptionError, SharedSecret},
     metrics::EndpointMetrics,
     net_report::{self, IpMappedAddresses, Report, ReportError},
@@ -95,6 +97,9 @@ const ENDPOINTS_FRESH_ENOUGH_DURATION: Duration = Duration::from_secs(27);
 
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 
+/// The maximum number of [`NetworkChange`]s held for stalled subscribers.
+const NETWORK_CHANGES_CAPACITY: usize = 16;
+
 /// Contains options for `MagicSock::listen`.
 #[derive(derive_more::Debug)]
 pub(crate) struct Options {
@@ -106,6 +111,8 @@ pub(crate) struct Options {
     ///
     /// If set to `None` it will choose a random port and listen on `[::]:0`.
     pub(crate) addr_v6: Option<SocketAddrV6>,
+    /// Which IP address families to bind sockets for.
+    pub(crate) ip_stack: IpStack,
 
     /// Secret key for this node.
     pub(crate) secret_key: SecretKey,
@@ -201,7 +208,9 @@ pub(crate) struct MagicSock {
     ipv6_reported: Arc<AtomicBool>,
 
     /// Zero nodes means relay is disabled.
-    relay_map: RelayMap,
+    ///
+    /// Shared with the relay actor, see [`MagicSock::set_relay_map`].
+    relay_map: Arc<RwLock<RelayMap>>,
     /// Tracks the networkmap node entity for each node discovery key.
     node_map: NodeMap,
     /// Tracks the mapped IP addresses
@@ -215,7 +224,7 @@ pub(crate) struct MagicSock {
     disco_sender: mpsc::Sender<(SendAddr, PublicKey, disco::Message)>,
 
     /// Optional discovery service
-    discovery: Option<Box<dyn Discovery>>,
+    discovery: SwappableDiscovery,
 
     /// Optional user-defined discover data.
     discovery_user_data: RwLock<Option<UserData>>,
@@ -235,6 +244,8 @@ pub(crate) struct MagicSock {
 
     /// Broadcast channel for listening to discovery updates.
     discovery_subscribers: DiscoverySubscribers,
+    /// Broadcast channel for listening to network changes.
+    network_changes: sync::broadcast::Sender<NetworkChange>,
 
     pub(crate) metrics: EndpointMetrics,
 
@@ -396,6 +407,19 @@ impl MagicSock {
         self.node_map.conn_type(node_id)
     }
 
+    /// Returns the IP address a QUIC packet from `addr` was received from.
+    ///
+    /// `addr` is a remote address as seen by the QUIC layer.  Returns `None` if the packets
+    /// of this remote are received via a relay server.
+    pub(crate) fn remote_ip(&self, addr: SocketAddr) -> Option<IpAddr> {
+        match MappedAddr::from(addr) {
+            MappedAddr::NodeId(addr) => self.node_map.last_received_ip(addr),
+            #[cfg(not(wasm_browser))]
+            MappedAddr::Ip(addr) => self.ip_mapped_addrs.get_ip_addr(&addr).map(|a| a.ip()),
+            MappedAddr::None(addr) => Some(addr.ip()),
+        }
+    }
+
     /// Returns the socket address which can be used by the QUIC layer to dial this node.
     pub(crate) fn get_mapping_addr(&self, node_id: NodeId) -> Option<NodeIdMappedAddr> {
         self.node_map.get_quic_mapped_addr_for_node_key(node_id)
@@ -446,8 +470,43 @@ impl MagicSock {
     }
 
     /// Reference to optional discovery service
+    ///
+    /// This delegates to the current discovery service, also after it was replaced.
     pub(crate) fn discovery(&self) -> Option<&dyn Discovery> {
-        self.discovery.as_ref().map(Box::as_ref)
+        self.discovery
+            .current()
+            .is_some()
+            .then_some(&self.discovery as &dyn Discovery)
+    }
+
+    /// Returns the current discovery service.
+    pub(crate) fn current_discovery(&self) -> Option<Arc<dyn Discovery>> {
+        self.discovery.current()
+    }
+
+    /// Replaces the discovery service.
+    ///
+    /// The actor subscribes to the new service and our address is published to it.
+    pub(crate) async fn set_discovery(&self, discovery: Option<Box<dyn Discovery>>) {
+        self.discovery.set(discovery.map(Arc::from));
+        self.publish_my_addr();
+        self.actor_sender
+            .send(ActorMessage::DiscoveryChange)
+            .await
+            .ok();
+    }
+
+    /// Returns the current relay map.
+    pub(crate) fn relay_map(&self) -> RelayMap {
+        self.relay_map.read().expect("lock poisened").clone()
+    }
+
+    /// Replaces the relay map.
+    ///
+    /// Triggers a new net report, which selects the home relay from the new map.
+    pub(crate) fn set_relay_map(&self, relay_map: RelayMap) {
+        *self.relay_map.write().expect("lock poisened") = relay_map;
+        self.re_stun("relay-map-change");
     }
 
     /// Updates the user-defined discovery data for this node.
@@ -473,6 +532,13 @@ impl MagicSock {
         &self.discovery_subscribers
     }
 
+    /// Returns a stream of the network changes handled by the magicsock.
+    pub(crate) fn network_changes(&self) -> impl Stream<Item = Result<NetworkChange, Lagged>> {
+        use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+        let recv = self.network_changes.subscribe();
+        BroadcastStream::new(recv).map_err(|BroadcastStreamRecvError::Lagged(n)| Lagged { val: n })
+    }
+
     #[cfg(test)]
     async fn force_network_change(&self, is_major: bool) {
         self.actor_sender
@@ -1105,7 +1171,7 @@ impl MagicSock {
     ///
     /// Called whenever our addresses or home relay node changes.
     fn publish_my_addr(&self) {
-        if let Some(ref discovery) = self.discovery {
+        if let Some(discovery) = self.current_discovery() {
             let relay_url = self.my_relay();
             let direct_addrs = self.direct_addrs.sockaddrs();
 
@@ -1125,6 +1191,44 @@ impl MagicSock {
     }
 }
 
+/// A discovery service which can be replaced while it is in use.
+#[derive(Debug)]
+struct SwappableDiscovery(RwLock<Option<Arc<dyn Discovery>>>);
+
+impl SwappableDiscovery {
+    fn new(discovery: Option<Arc<dyn Discovery>>) -> Self {
+        Self(RwLock::new(discovery))
+    }
+
+    fn current(&self) -> Option<Arc<dyn Discovery>> {
+        self.0.read().expect("poisoned").clone()
+    }
+
+    fn set(&self, discovery: Option<Arc<dyn Discovery>>) {
+        *self.0.write().expect("poisoned") = discovery;
+    }
+}
+
+impl Discovery for SwappableDiscovery {
+    fn publish(&self, data: &NodeData) {
+        if let Some(discovery) = self.current() {
+            discovery.publish(data);
+        }
+    }
+
+    fn resolve(
+        &self,
+        endpoint: crate::Endpoint,
+        node_id: NodeId,
+    ) -> Option<BoxStream<Result<DiscoveryItem, DiscoveryError>>> {
+        self.current()?.resolve(endpoint, node_id)
+    }
+
+    fn subscribe(&self) -> Option<BoxStream<DiscoveryItem>> {
+        self.current()?.subscribe()
+    }
+}
+
 #[derive(Clone, Debug)]
 enum MappedAddr {
     NodeId(NodeIdMappedAddr),
@@ -1240,6 +1344,7 @@ impl Handle {
         let Options {
             addr_v4,
             addr_v6,
+            ip_stack,
             secret_key,
             relay_map,
             relay_protocol,
@@ -1257,18 +1362,18 @@ impl Handle {
             metrics,
         } = opts;
 
-        let addr_v4 = addr_v4.unwrap_or_else(|| SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
-
         #[cfg(not(wasm_browser))]
         let (ip_transports, port_mapper) =
-            bind_ip(addr_v4, addr_v6, &metrics).context(BindSocketsSnafu)?;
+            bind_ip(addr_v4, addr_v6, ip_stack, &metrics).context(BindSocketsSnafu)?;
 
         #[cfg(not(wasm_browser))]
-        let v4_socket = ip_transports
-            .iter()
-            .find(|t| t.bind_addr().is_ipv4())
-            .expect("must bind a ipv4 socket")
-            .socket();
+        let v4_socket = ip_transports.iter().find_map(|t| {
+            if t.bind_addr().is_ipv4() {
+                Some(t.socket())
+            } else {
+                None
+            }
+        });
         #[cfg(not(wasm_browser))]
         let v6_socket = ip_transports.iter().find_map(|t| {
             if t.bind_addr().is_ipv6() {
@@ -1303,8 +1408,10 @@ impl Handle {
         let my_relay = Watchable::new(None);
         let ipv6_reported = Arc::new(AtomicBool::new(false));
 
+        let relay_map = Arc::new(RwLock::new(relay_map));
         let relay_transport = RelayTransport::new(RelayActorConfig {
             my_relay: my_relay.clone(),
+            relay_map: relay_map.clone(),
             secret_key: secret_key.clone(),
             #[cfg(not(wasm_browser))]
             dns_resolver: dns_resolver.clone(),
@@ -1340,7 +1447,7 @@ impl Handle {
             node_map,
             ip_mapped_addrs,
             disco_sender,
-            discovery,
+            discovery: SwappableDiscovery::new(discovery.map(Arc::from)),
             discovery_user_data: RwLock::new(discovery_user_data),
             direct_addrs: Default::default(),
             net_report: Default::default(),
@@ -1349,6 +1456,7 @@ impl Handle {
             #[cfg(not(wasm_browser))]
             dns_resolver,
             discovery_subscribers: DiscoverySubscribers::new(),
+            network_changes: sync::broadcast::Sender::new(NETWORK_CHANGES_CAPACITY),
             metrics,
             local_addrs_watch: transports.local_addrs_watch(),
             #[cfg(not(wasm_browser))]
@@ -1413,7 +1521,7 @@ impl Handle {
         let net_report_config = net_report::Options::default();
         #[cfg(not(wasm_browser))]
         let net_report_config = net_report_config
-            .stun_v4(Some(v4_socket))
+            .stun_v4(v4_socket)
             .stun_v6(v6_socket)
             .quic_config(Some(QuicConfig {
                 ep: qad_endpoint,
@@ -1673,6 +1781,7 @@ enum ActorMessage {
         &'static str,
     ),
     NetworkChange,
+    DiscoveryChange,
     #[cfg(test)]
     ForceNetworkChange(bool),
 }
@@ -1706,41 +1815,52 @@ struct Actor {
 
 #[cfg(not(wasm_browser))]
 fn bind_ip(
-    addr_v4: SocketAddrV4,
+    addr_v4: Option<SocketAddrV4>,
     addr_v6: Option<SocketAddrV6>,
+    ip_stack: IpStack,
     metrics: &EndpointMetrics,
 ) -> io::Result<(Vec<IpTransport>, portmapper::Client)> {
     let port_mapper =
         portmapper::Client::with_metrics(Default::default(), metrics.portmapper.clone());
 
-    let v4 = Arc::new(bind_with_fallback(SocketAddr::V4(addr_v4))?);
-    let ip4_port = v4.local_addr()?.port();
-    let ip6_port = ip4_port.checked_add(1).unwrap_or(ip4_port - 1);
-
-    let addr_v6 =
-        addr_v6.unwrap_or_else(|| SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, ip6_port, 0, 0));
-
-    let v6 = match bind_with_fallback(SocketAddr::V6(addr_v6)) {
-        Ok(sock) => Some(Arc::new(sock)),
-        Err(err) => {
-            info!("bind ignoring IPv6 bind failure: {:?}", err);
-            None
-        }
-    };
+    let mut ip = Vec::new();
+    let mut port = 0;
 
-    let port = v4.local_addr().map_or(0, |p| p.port());
-
-    let mut ip = vec![IpTransport::new(
-        addr_v4.into(),
-        v4,
-        metrics.magicsock.clone(),
-    )];
-    if let Some(v6) = v6 {
+    if ip_stack != IpStack::Ipv6Only {
+        let addr_v4 = addr_v4.unwrap_or_else(|| SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+        let v4 = Arc::new(bind_with_fallback(SocketAddr::V4(addr_v4))?);
+        port = v4.local_addr().map_or(0, |p| p.port());
         ip.push(IpTransport::new(
-            addr_v6.into(),
-            v6,
+            addr_v4.into(),
+            v4,
             metrics.magicsock.clone(),
-        ))
+        ));
+    }
+
+    if ip_stack != IpStack::Ipv4Only {
+        let ip6_port = match port {
+            0 => 0,
+            port => port.checked_add(1).unwrap_or(port - 1),
+        };
+        let addr_v6 =
+            addr_v6.unwrap_or_else(|| SocketAddrV6::new(Ipv6Addr::UNSPECIFIED, ip6_port, 0, 0));
+
+        match bind_with_fallback(SocketAddr::V6(addr_v6)) {
+            Ok(v6) => {
+                if ip_stack == IpStack::Ipv6Only {
+                    port = v6.local_addr().map_or(0, |p| p.port());
+                }
+                ip.push(IpTransport::new(
+                    addr_v6.into(),
+                    Arc::new(v6),
+                    metrics.magicsock.clone(),
+                ))
+            }
+            Err(err) if ip_stack == IpStack::Ipv6Only => return Err(err),
+            Err(err) => {
+                info!("bind ignoring IPv6 bind failure: {:?}", err);
+            }
+        }
     }
 
     // NOTE: we can end up with a zero port if `netwatch::UdpSocket::socket_addr` fails
@@ -1782,12 +1902,7 @@ impl Actor {
         #[cfg(not(wasm_browser))]
         let mut portmap_watcher = self.port_mapper.watch_external_address();
 
-        let mut discovery_events: BoxStream<DiscoveryItem> = Box::pin(n0_future::stream::empty());
-        if let Some(d) = self.msock.discovery() {
-            if let Some(events) = d.subscribe() {
-                discovery_events = events;
-            }
-        }
+        let mut discovery_events = self.subscribe_discovery();
 
         let mut receiver_closed = false;
         #[cfg_attr(wasm_browser, allow(unused_mut))]
@@ -1817,7 +1932,7 @@ impl Actor {
 
                     trace!(?msg, "tick: msg");
                     self.msock.metrics.magicsock.actor_tick_msg.inc();
-                    if self.handle_actor_message(msg, &sender).await {
+                    if self.handle_actor_message(msg, &sender, &mut discovery_events).await {
                         return;
                     }
                 }
@@ -1931,6 +2046,12 @@ impl Actor {
         } else {
             self.msock.re_stun("link-change-minor");
         }
+
+        // Having no subscribers is not an error.
+        self.msock
+            .network_changes
+            .send(NetworkChange { is_major })
+            .ok();
     }
 
     #[instrument(skip_all)]
@@ -1940,10 +2061,25 @@ impl Actor {
         }
     }
 
+    /// Subscribes to the events of the current discovery service.
+    fn subscribe_discovery(&self) -> BoxStream<DiscoveryItem> {
+        self.msock
+            .current_discovery()
+            .and_then(|d| d.subscribe())
+            .unwrap_or_else(|| Box::pin(n0_future::stream::empty()))
+    }
+
     /// Processes an incoming actor message.
     ///
+    /// `discovery_events` is replaced when the discovery service changed.
+    ///
     /// Returns `true` if it was a shutdown.
-    async fn handle_actor_message(&mut self, msg: ActorMessage, sender: &UdpSender) -> bool {
+    async fn handle_actor_message(
+        &mut self,
+        msg: ActorMessage,
+        sender: &UdpSender,
+        discovery_events: &mut BoxStream<DiscoveryItem>,
+    ) -> bool {
         match msg {
             ActorMessage::Shutdown => {
                 debug!("shutting down");
@@ -1975,6 +2111,9 @@ impl Actor {
             ActorMessage::NetworkChange => {
                 self.network_monitor.network_change().await.ok();
             }
+            ActorMessage::DiscoveryChange => {
+                *discovery_events = self.subscribe_discovery();
+            }
             #[cfg(test)]
             ActorMessage::ForceNetworkChange(is_major) => {
                 self.handle_network_change(is_major);
@@ -2209,7 +2348,8 @@ impl Actor {
             debug!("skipping net_report, socket is shutting down");
             return;
         }
-        if self.msock.relay_map.is_empty() {
+        let relay_map = self.msock.relay_map();
+        if relay_map.is_empty() {
             debug!("skipping net_report, empty RelayMap");
             self.msg_sender
                 .send(ActorMessage::NetReport(Ok(None), why))
@@ -2218,7 +2358,6 @@ impl Actor {
             return;
         }
 
-        let relay_map = self.msock.relay_map.clone();
         let opts = self.net_report_config.clone();
 
         debug!("requesting net_report report");
@@ -2301,6 +2440,10 @@ impl Actor {
 
             // TODO: set link type
             self.call_net_info_callback(ni).await;
+        } else if self.msock.relay_map().is_empty() {
+            // Without relay servers there is no home relay.
+            self.network_change_sender
+                .on_network_change(&NetInfo::default());
         }
         #[cfg(not(wasm_browser))]
         self.update_direct_addresses(report);
@@ -2322,12 +2465,16 @@ impl Actor {
         //
         // We used to do the above for legacy clients, but never updated it for disco.
 
+        let relay_map = self.msock.relay_map();
         let my_relay = self.msock.my_relay();
-        if my_relay.is_some() {
+        if my_relay
+            .as_ref()
+            .is_some_and(|url| relay_map.contains_node(url))
+        {
             return my_relay;
         }
 
-        let ids = self.msock.relay_map.urls().collect::<Vec<_>>();
+        let ids = relay_map.urls().collect::<Vec<_>>();
         let mut rng = rand::rngs::StdRng::seed_from_u64(0);
         ids.choose(&mut rng).map(|c| (*c).clone())
     }
@@ -2607,8 +2754,42 @@ impl Display for DirectAddrType {
     }
 }
 
+/// The IP address families an endpoint binds sockets for.
+///
+/// See [`crate::endpoint::Builder::ip_stack`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum IpStack {
+    /// Binds both an IPv4 and an IPv6 socket.
+    ///
+    /// Failing to bind the IPv6 socket is not an error, the endpoint then only uses IPv4.
+    #[default]
+    DualStack,
+    /// Binds only an IPv4 socket, IPv6 is not used.
+    Ipv4Only,
+    /// Binds only an IPv6 socket, IPv4 is not used.
+    Ipv6Only,
+}
+
+/// A change of the local network, as handled by the endpoint.
+///
+/// Connections are not bound to the local addresses in use when they were established, so
+/// they survive network changes: after a change the endpoint rebinds its sockets if needed
+/// and re-establishes the paths to the remote nodes, migrating all connections to the new
+/// paths.  Until a new direct path is found the connections continue over the relay.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct NetworkChange {
+    /// Whether this was a major change.
+    ///
+    /// A major change, e.g. switching from Wi-Fi to cellular, invalidates all paths to
+    /// remote nodes.  Minor changes only cause the endpoint to re-check its public
+    /// addresses.
+    pub is_major: bool,
+}
+
 /// Contains information about the host's network state.
-#[derive(Debug, Clone, PartialEq)]
+#[derive(Debug, Clone, Default, PartialEq)]
 pub(crate) struct NetInfo {
     /// Says whether the host's NAT mappings vary based on the destination IP.
     mapping_varies_by_dest_ip: Option<bool>,
@@ -2719,6 +2900,7 @@ mod tests {
             Options {
                 addr_v4: None,
                 addr_v6: None,
+                ip_stack: Default::default(),
                 secret_key,
                 relay_map: RelayMap::empty(),
                 relay_protocol: iroh_relay::http::Protocol::default(),
@@ -3049,6 +3231,30 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    #[traced_test]
+    async fn test_network_changes_stream() -> Result {
+        let m = MagicStack::new(RelayMode::Disabled).await;
+        let mut changes = m.endpoint.network_changes();
+
+        m.endpoint.magic_sock().force_network_change(true).await;
+        let change = time::timeout(Duration::from_secs(5), changes.next())
+            .await
+            .e()?
+            .expect("stream not closed")
+            .e()?;
+        assert!(change.is_major);
+
+        m.endpoint.magic_sock().force_network_change(false).await;
+        let change = time::timeout(Duration::from_secs(5), changes.next())
+            .await
+            .e()?
+            .expect("stream not closed")
+            .e()?;
+        assert!(!change.is_major);
+        Ok(())
+    }
+
     #[tokio::test]
     #[traced_test]
     async fn test_regression_network_change_rebind_wakes_connection_driver() -> n0_snafu::Result {
@@ -3252,6 +3458,7 @@ mod tests {
         let opts = Options {
             addr_v4: None,
             addr_v6: None,
+            ip_stack: Default::default(),
             secret_key: secret_key.clone(),
             relay_map: RelayMap::empty(),
             relay_protocol: iroh_relay::http::Protocol::default(),
@@ -211,6 +211,17 @@ impl NodeMap {
             .map(|ep| *ep.quic_mapped_addr())
     }
 
+    /// Returns the IP address payload from the node at `addr` was last received from.
+    ///
+    /// Returns `None` if the node is unknown or payload was last received via a relay.
+    pub(super) fn last_received_ip(&self, addr: NodeIdMappedAddr) -> Option<IpAddr> {
+        self.inner
+            .lock()
+            .expect("poisoned")
+            .get(NodeStateKey::NodeIdMappedAddr(addr))
+            .and_then(|ep| ep.last_received_ip())
+    }
+
     /// Insert a received ping into the node map, and return whether a ping with this tx_id was already
     /// received.
     pub(super) fn handle_ping(
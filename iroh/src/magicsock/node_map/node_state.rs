@@ -1070,6 +1070,26 @@ impl NodeState {
             .reconfirm_if_used(addr.into(), BestAddrSource::Udp, now);
     }
 
+    /// Returns the IP address payload was last received from.
+    ///
+    /// Returns `None` if payload was last received via the relay, or not at all.
+    pub(super) fn last_received_ip(&self) -> Option<IpAddr> {
+        let (ip_port, udp_last) = self
+            .udp_paths
+            .paths
+            .iter()
+            .filter_map(|(ip_port, state)| Some((ip_port, state.last_payload_msg?)))
+            .max_by_key(|(_, last)| *last)?;
+        let relay_last = self
+            .relay_url
+            .as_ref()
+            .and_then(|(_, state)| state.last_payload_msg);
+        if relay_last.is_some_and(|relay_last| relay_last > udp_last) {
+            return None;
+        }
+        Some(ip_port.ip)
+    }
+
     pub(super) fn receive_relay(&mut self, url: &RelayUrl, src: NodeId, now: Instant) {
         match self.relay_url.as_mut() {
             Some((current_home, state)) if current_home == url => {
@@ -43,7 +43,7 @@ use iroh_base::{NodeId, PublicKey, RelayUrl, SecretKey};
 use iroh_relay::{
     self as relay,
     client::{Client, ConnectError, ReceivedMessage, RecvError, SendError, SendMessage},
-    PingTracker, MAX_PACKET_SIZE,
+    PingTracker, RelayMap, RelayTlsConfig, MAX_PACKET_SIZE,
 };
 use n0_future::{
     task::JoinSet,
@@ -212,6 +212,8 @@ struct RelayConnectionOptions {
     dns_resolver: DnsResolver,
     proxy_url: Option<Url>,
     prefer_ipv6: Arc<AtomicBool>,
+    #[cfg(not(wasm_browser))]
+    tls_config: RelayTlsConfig,
     #[cfg(any(test, feature = "test-utils"))]
     insecure_skip_cert_verify: bool,
     protocol: iroh_relay::http::Protocol,
@@ -312,6 +314,8 @@ impl ActiveRelayActor {
             dns_resolver,
             proxy_url,
             prefer_ipv6,
+            #[cfg(not(wasm_browser))]
+            tls_config,
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_cert_verify,
             protocol,
@@ -325,6 +329,10 @@ impl ActiveRelayActor {
         )
         .protocol(protocol)
         .address_family_selector(move || prefer_ipv6.load(Ordering::Relaxed));
+        #[cfg(not(wasm_browser))]
+        {
+            builder = builder.tls_config(tls_config);
+        }
         if let Some(proxy_url) = proxy_url {
             builder = builder.proxy_url(proxy_url);
         }
@@ -874,6 +882,8 @@ pub(super) struct RelayActor {
 #[derive(Debug)]
 pub struct Config {
     pub my_relay: Watchable<Option<RelayUrl>>,
+    /// The relay servers, used to look up their TLS configuration.
+    pub relay_map: Arc<std::sync::RwLock<RelayMap>>,
     pub secret_key: SecretKey,
     #[cfg(not(wasm_browser))]
     pub dns_resolver: DnsResolver,
@@ -1019,20 +1029,27 @@ impl RelayActor {
             .set(info.preferred_relay.clone())
             .unwrap_or_else(|e| e);
 
-        if let Some(relay_url) = info.preferred_relay {
-            self.config.metrics.relay_home_change.inc();
+        match info.preferred_relay {
+            Some(relay_url) => {
+                self.config.metrics.relay_home_change.inc();
 
-            // On change, notify all currently connected relay servers and
-            // start connecting to our home relay if we are not already.
-            info!("home is now relay {}, was {:?}", relay_url, old_relay);
-            self.set_home_relay(relay_url).await;
+                // On change, notify all currently connected relay servers and
+                // start connecting to our home relay if we are not already.
+                info!("home is now relay {}, was {:?}", relay_url, old_relay);
+                self.set_home_relay(Some(relay_url)).await;
+            }
+            None => {
+                // E.g. the relay map was emptied, none of the relays is our home anymore.
+                info!("no home relay, was {:?}", old_relay);
+                self.set_home_relay(None).await;
+            }
         }
     }
 
-    async fn set_home_relay(&mut self, home_url: RelayUrl) {
-        let home_url_ref = &home_url;
+    async fn set_home_relay(&mut self, home_url: Option<RelayUrl>) {
+        let home_url_ref = home_url.as_ref();
         n0_future::join_all(self.active_relays.iter().map(|(url, handle)| async move {
-            let is_preferred = url == home_url_ref;
+            let is_preferred = Some(url) == home_url_ref;
             handle
                 .inbox_addr
                 .send(ActiveRelayMessage::SetHomeRelay(is_preferred))
@@ -1041,7 +1058,9 @@ impl RelayActor {
         }))
         .await;
         // Ensure we have an ActiveRelayActor for the current home relay.
-        self.active_relay_handle(home_url);
+        if let Some(home_url) = home_url {
+            self.active_relay_handle(home_url);
+        }
     }
 
     /// Returns the handle for the [`ActiveRelayActor`] to reach `remote_node`.
@@ -1118,6 +1137,15 @@ impl RelayActor {
             dns_resolver: self.config.dns_resolver.clone(),
             proxy_url: self.config.proxy_url.clone(),
             prefer_ipv6: self.config.ipv6_reported.clone(),
+            #[cfg(not(wasm_browser))]
+            tls_config: self
+                .config
+                .relay_map
+                .read()
+                .expect("poisoned")
+                .get_node(&url)
+                .map(|node| node.tls.clone())
+                .unwrap_or_default(),
             #[cfg(any(test, feature = "test-utils"))]
             insecure_skip_cert_verify: self.config.insecure_skip_relay_cert_verify,
             protocol: self.config.protocol,
@@ -1444,6 +1472,7 @@ mod tests {
                 dns_resolver: DnsResolver::new(),
                 proxy_url: None,
                 prefer_ipv6: Arc::new(AtomicBool::new(true)),
+                tls_config: Default::default(),
                 insecure_skip_cert_verify: true,
                 protocol: iroh_relay::http::Protocol::default(),
             },
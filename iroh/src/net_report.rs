@@ -927,6 +927,7 @@ mod test_utils {
             stun_only: false, // the checks above and below guarantee both stun and relay
             stun_port: server.stun_addr().expect("server should serve stun").port(),
             quic,
+            tls: Default::default(),
         };
 
         (server, node_desc)
@@ -1012,6 +1013,7 @@ mod tests {
                     stun_port: port,
                     stun_only,
                     quic: None,
+                    tls: Default::default(),
                 }
             });
             RelayMap::from_iter(nodes)
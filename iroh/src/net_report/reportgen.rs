@@ -815,6 +815,10 @@ pub enum QuicError {
     NoEndpoint,
     #[snafu(display("URL must have 'host' to use QUIC address discovery probes"))]
     InvalidUrl,
+    #[snafu(display("Invalid relay TLS configuration"))]
+    TlsConfig {
+        source: iroh_relay::RelayTlsConfigError,
+    },
     #[snafu(display("Failed to create QUIC endpoint"))]
     CreateClient { source: iroh_relay::quic::Error },
     #[snafu(display("Failed to get address and latency"))]
@@ -951,7 +955,22 @@ async fn run_probe(
             debug!("sending QUIC address discovery probe");
             let url = node.url.clone();
             match socket_state.quic_config {
-                Some(quic_config) => {
+                Some(mut quic_config) => {
+                    // Relays with their own TLS configuration can not use the shared one,
+                    // unless certificate verification is disabled altogether.
+                    #[cfg(any(test, feature = "test-utils"))]
+                    let use_node_tls = !node.tls.is_default() && !insecure_skip_relay_cert_verify;
+                    #[cfg(not(any(test, feature = "test-utils")))]
+                    let use_node_tls = !node.tls.is_default();
+                    if use_node_tls {
+                        quic_config.client_config = node.tls.client_config().map_err(|e| {
+                            ProbeErrorWithProbe::Error(
+                                probe_error::QuicSnafu
+                                    .into_error(quic_error::TlsConfigSnafu.into_error(e)),
+                                probe.clone(),
+                            )
+                        })?;
+                    }
                     result = run_quic_probe(
                         quic_config,
                         url,
@@ -1435,6 +1454,11 @@ enum MeasureHttpsLatencyError {
     #[cfg(not(wasm_browser))]
     #[snafu(transparent)]
     DnsLookup { source: StaggeredError<DnsError> },
+    #[cfg(not(wasm_browser))]
+    #[snafu(display("Invalid relay TLS configuration"))]
+    TlsConfig {
+        source: iroh_relay::RelayTlsConfigError,
+    },
     #[snafu(display("Creating HTTP client failed"))]
     CreateReqwestClient { source: reqwest::Error },
     #[snafu(display("HTTP request failed"))]
@@ -1445,8 +1469,8 @@ enum MeasureHttpsLatencyError {
 
 /// Executes an HTTPS probe.
 ///
-/// If `certs` is provided they will be added to the trusted root certificates, allowing the
-/// use of self-signed certificates for servers.  Currently this is used for testing.
+/// The relay's certificate is verified according to its [`RelayNode::tls`] configuration,
+/// like the relay client does.
 #[allow(clippy::unused_async)]
 async fn measure_https_latency(
     #[cfg(not(wasm_browser))] dns_resolver: &DnsResolver,
@@ -1482,6 +1506,21 @@ async fn measure_https_latency(
         builder = builder.resolve_to_addrs(domain, &addrs);
     }
 
+    // Relays with their own TLS configuration are verified the same way as by the relay
+    // client.
+    #[cfg(all(not(wasm_browser), any(test, feature = "test-utils")))]
+    let use_node_tls = !node.tls.is_default() && !insecure_skip_relay_cert_verify;
+    #[cfg(all(not(wasm_browser), not(any(test, feature = "test-utils"))))]
+    let use_node_tls = !node.tls.is_default();
+    #[cfg(not(wasm_browser))]
+    if use_node_tls {
+        let tls_config = node
+            .tls
+            .client_config()
+            .context(measure_https_latency_error::TlsConfigSnafu)?;
+        builder = builder.use_preconfigured_tls(tls_config);
+    }
+
     #[cfg(all(not(wasm_browser), any(test, feature = "test-utils")))]
     let builder = builder.danger_accept_invalid_certs(insecure_skip_relay_cert_verify);
 
@@ -1622,6 +1661,7 @@ impl<T: Future + Unpin> Future for MaybeFuture<T> {
 mod tests {
     use std::net::{Ipv4Addr, Ipv6Addr};
 
+    use iroh_relay::RelayTlsConfig;
     use n0_snafu::{Result, ResultExt};
     use tracing_test::traced_test;
 
@@ -1897,6 +1937,33 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_measure_https_latency_relay_tls() -> Result {
+        let (server, mut relay) = test_utils::relay().await;
+        let dns_resolver = dns::tests::resolver();
+        let cert = server.certificates().unwrap().remove(0);
+        let spki = webpki::EndEntityCert::try_from(&cert)
+            .unwrap()
+            .subject_public_key_info();
+
+        // The self-signed certificate is not trusted by default.
+        assert!(measure_https_latency(&dns_resolver, &relay, false)
+            .await
+            .is_err());
+
+        relay.tls = RelayTlsConfig {
+            root_certs: vec![cert.to_vec()],
+            spki_sha256_pins: vec![RelayTlsConfig::spki_sha256(&spki)],
+        };
+        measure_https_latency(&dns_resolver, &relay, false).await?;
+
+        relay.tls.spki_sha256_pins = vec![[0u8; 32]];
+        assert!(measure_https_latency(&dns_resolver, &relay, false)
+            .await
+            .is_err());
+        Ok(())
+    }
+
     #[tokio::test]
     #[traced_test]
     async fn test_quic_probe() -> Result {
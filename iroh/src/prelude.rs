@@ -0,0 +1,25 @@
+//! The most commonly used types and traits, for glob importing.
+//!
+//! ```no_run
+//! use iroh::prelude::*;
+//!
+//! # async fn wrapper() -> n0_snafu::Result {
+//! let endpoint = Endpoint::builder().discovery_n0().bind().await?;
+//! let addr: NodeAddr = endpoint.node_addr().initialized().await?;
+//! let router = Router::builder(endpoint).spawn();
+//! # drop(addr);
+//! # router.shutdown().await.ok();
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Items are only removed from the prelude in a breaking release, but new items may be
+//! added in any release.
+
+pub use n0_watcher::Watcher;
+
+pub use crate::{
+    endpoint::{Connection, Endpoint, RelayMode},
+    protocol::{AcceptError, ProtocolHandler, Router},
+    NodeAddr, NodeId, PublicKey, RelayUrl, SecretKey,
+};
@@ -0,0 +1,432 @@
+//! Utilities for building message-based protocols on top of QUIC streams.
+//!
+//! QUIC streams are plain byte streams, so every custom protocol which exchanges discrete
+//! messages needs some framing.  This module provides a simple length-delimited framing
+//! which most such protocols can reuse:
+//!
+//! - Each message is sent as a frame carrying its length, so the receiver gets exactly
+//!   the messages the sender sent.
+//! - Heartbeat frames can be sent while there is nothing else to say, allowing the
+//!   receiver to detect a stalled peer using [`MessageReceiver::idle_timeout`].
+//! - A close frame marks the graceful end of the message stream.  This lets the receiver
+//!   tell a peer which finished talking apart from a stream which was cut short.
+//!
+//! Use [`framed`] to wrap the two halves of a bi-directional stream.
+//!
+//! ## Example
+//!
+//! ```no_run
+//! # use iroh::{endpoint::Connection, proto_util};
+//! # use n0_snafu::ResultExt;
+//! # async fn wrapper(conn: Connection) -> n0_snafu::Result {
+//! let (send, recv) = conn.open_bi().await.e()?;
+//! let (mut sender, mut receiver) = proto_util::framed(send, recv);
+//!
+//! sender.send(b"hello").await?;
+//! sender.close().await?;
+//!
+//! while let Some(msg) = receiver.recv().await? {
+//!     println!("received {} bytes", msg.len());
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use bytes::{Bytes, BytesMut};
+use n0_future::time::{self, Duration};
+use nested_enum_utils::common_fields;
+use snafu::{ensure, Snafu};
+
+use crate::endpoint::{ClosedStream, ReadExactError, RecvStream, SendStream, WriteError};
+
+/// The default maximum size of a single message accepted by a [`MessageReceiver`].
+pub const DEFAULT_MAX_MESSAGE_SIZE: usize = 1024 * 1024;
+
+/// The interval at which we recommend sending heartbeats on an otherwise idle stream.
+///
+/// This is a third of [`DEFAULT_IDLE_TIMEOUT`], so a couple of heartbeats can be delayed
+/// before the receiving side considers the sender gone.
+pub const DEFAULT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The recommended idle timeout for a [`MessageReceiver`] if the sender sends heartbeats.
+pub const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Frame tag of a message frame, followed by a big-endian `u32` length and the payload.
+const FRAME_MESSAGE: u8 = 0;
+/// Frame tag of a heartbeat frame, which carries no payload.
+const FRAME_HEARTBEAT: u8 = 1;
+/// Frame tag of the close frame, which carries no payload and ends the message stream.
+const FRAME_CLOSE: u8 = 2;
+
+/// Wraps the two halves of a bi-directional stream into a message sender and receiver.
+///
+/// The receiver uses [`DEFAULT_MAX_MESSAGE_SIZE`] and no idle timeout, use the methods
+/// on [`MessageReceiver`] to change these.
+pub fn framed(send: SendStream, recv: RecvStream) -> (MessageSender, MessageReceiver) {
+    (MessageSender::new(send), MessageReceiver::new(recv))
+}
+
+/// Error when sending a message with a [`MessageSender`].
+#[common_fields({
+    backtrace: Option<snafu::Backtrace>,
+    #[snafu(implicit)]
+    span_trace: n0_snafu::SpanTrace,
+})]
+#[allow(missing_docs)]
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+#[non_exhaustive]
+pub enum SendError {
+    #[snafu(transparent)]
+    Write { source: WriteError },
+    #[snafu(transparent)]
+    Closed { source: ClosedStream },
+    #[snafu(display("Message of {len} bytes is too large to be framed"))]
+    TooLarge { len: usize },
+}
+
+/// Error when receiving a message with a [`MessageReceiver`].
+#[common_fields({
+    backtrace: Option<snafu::Backtrace>,
+    #[snafu(implicit)]
+    span_trace: n0_snafu::SpanTrace,
+})]
+#[allow(missing_docs)]
+#[derive(Debug, Snafu)]
+#[snafu(module)]
+#[non_exhaustive]
+pub enum RecvError {
+    #[snafu(display("Stream ended without a close frame or in the middle of a frame"))]
+    UnexpectedEnd {},
+    #[snafu(transparent)]
+    Read { source: ReadExactError },
+    #[snafu(display("Message of {len} bytes exceeds the limit of {max} bytes"))]
+    TooLarge { len: usize, max: usize },
+    #[snafu(display("Unknown frame type {tag}"))]
+    UnknownFrame { tag: u8 },
+    #[snafu(display("No frame received within {timeout:?}"))]
+    IdleTimeout { timeout: Duration },
+}
+
+/// The sending half of a framed message stream.
+///
+/// Created by [`framed`] or [`MessageSender::new`].
+#[derive(Debug)]
+pub struct MessageSender {
+    send: SendStream,
+}
+
+impl MessageSender {
+    /// Creates a new message sender writing frames to `send`.
+    pub fn new(send: SendStream) -> Self {
+        Self { send }
+    }
+
+    /// Sends a single message.
+    pub async fn send(&mut self, msg: &[u8]) -> Result<(), SendError> {
+        let len = u32::try_from(msg.len())
+            .map_err(|_| send_error::TooLargeSnafu { len: msg.len() }.build())?;
+        let mut header = [0u8; 5];
+        header[0] = FRAME_MESSAGE;
+        header[1..].copy_from_slice(&len.to_be_bytes());
+        self.send.write_all(&header).await?;
+        self.send.write_all(msg).await?;
+        Ok(())
+    }
+
+    /// Sends a heartbeat frame.
+    ///
+    /// Heartbeats are never returned from [`MessageReceiver::recv`], but they do reset
+    /// the receiver's idle timeout.  When there is nothing else to send, send these at
+    /// an interval shorter than the receiver's idle timeout, e.g.
+    /// [`DEFAULT_HEARTBEAT_INTERVAL`].
+    pub async fn heartbeat(&mut self) -> Result<(), SendError> {
+        self.send.write_all(&[FRAME_HEARTBEAT]).await?;
+        Ok(())
+    }
+
+    /// Gracefully closes the message stream.
+    ///
+    /// This sends a close frame and finishes the underlying stream.  Once the receiver
+    /// read all pending messages, [`MessageReceiver::recv`] will return `None`.
+    pub async fn close(mut self) -> Result<(), SendError> {
+        self.send.write_all(&[FRAME_CLOSE]).await?;
+        self.send.finish()?;
+        Ok(())
+    }
+
+    /// Returns the underlying [`SendStream`].
+    pub fn into_inner(self) -> SendStream {
+        self.send
+    }
+}
+
+/// The receiving half of a framed message stream.
+///
+/// Created by [`framed`] or [`MessageReceiver::new`].
+#[derive(Debug)]
+pub struct MessageReceiver {
+    recv: RecvStream,
+    max_message_size: usize,
+    idle_timeout: Option<Duration>,
+}
+
+impl MessageReceiver {
+    /// Creates a new message receiver reading frames from `recv`.
+    pub fn new(recv: RecvStream) -> Self {
+        Self {
+            recv,
+            max_message_size: DEFAULT_MAX_MESSAGE_SIZE,
+            idle_timeout: None,
+        }
+    }
+
+    /// Sets the maximum size of a single message.
+    ///
+    /// Receiving a larger message fails with [`RecvError::TooLarge`] without reading the
+    /// message.  Defaults to [`DEFAULT_MAX_MESSAGE_SIZE`].
+    pub fn max_message_size(mut self, max_message_size: usize) -> Self {
+        self.max_message_size = max_message_size;
+        self
+    }
+
+    /// Sets the maximum time to wait for a complete frame, including heartbeats.
+    ///
+    /// If the sender sends nothing, or stalls in the middle of a frame, for this long,
+    /// [`MessageReceiver::recv`] fails with [`RecvError::IdleTimeout`].  The timeout applies
+    /// to each frame, so it must be long enough to receive a message of the maximum size.  By
+    /// default there is no timeout.
+    pub fn idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.idle_timeout = Some(idle_timeout);
+        self
+    }
+
+    /// Receives the next message.
+    ///
+    /// Heartbeat frames are skipped.  Returns `None` once the sender closed the message
+    /// stream using [`MessageSender::close`].
+    ///
+    /// Errors are not recoverable.  On [`RecvError::IdleTimeout`], [`RecvError::TooLarge`]
+    /// and [`RecvError::UnknownFrame`] the underlying stream may be left in the middle of a
+    /// frame, so it is stopped and all further calls fail.
+    ///
+    /// This is not cancel-safe: if the future is dropped while a frame is partially read
+    /// the message stream is left in an undefined state, and must be dropped or stopped.
+    pub async fn recv(&mut self) -> Result<Option<Bytes>, RecvError> {
+        loop {
+            let res = match self.idle_timeout {
+                Some(timeout) => time::timeout(timeout, self.read_frame())
+                    .await
+                    .unwrap_or_else(|_| Err(recv_error::IdleTimeoutSnafu { timeout }.build())),
+                None => self.read_frame().await,
+            };
+            let frame = match res {
+                Ok(frame) => frame,
+                Err(err) => {
+                    if matches!(
+                        err,
+                        RecvError::IdleTimeout { .. }
+                            | RecvError::TooLarge { .. }
+                            | RecvError::UnknownFrame { .. }
+                    ) {
+                        // The rest of the stream can not be decoded anymore.
+                        self.recv.stop(0u32.into()).ok();
+                    }
+                    return Err(err);
+                }
+            };
+            match frame {
+                Frame::Message(message) => return Ok(Some(message)),
+                Frame::Heartbeat => continue,
+                Frame::Close => return Ok(None),
+            }
+        }
+    }
+
+    /// Returns the underlying [`RecvStream`].
+    pub fn into_inner(self) -> RecvStream {
+        self.recv
+    }
+
+    /// Reads a complete frame.
+    async fn read_frame(&mut self) -> Result<Frame, RecvError> {
+        match self.read_tag().await? {
+            FRAME_MESSAGE => self.read_message().await.map(Frame::Message),
+            FRAME_HEARTBEAT => Ok(Frame::Heartbeat),
+            FRAME_CLOSE => Ok(Frame::Close),
+            tag => Err(recv_error::UnknownFrameSnafu { tag }.build()),
+        }
+    }
+
+    async fn read_tag(&mut self) -> Result<u8, RecvError> {
+        let mut tag = [0u8; 1];
+        self.read_exact(&mut tag).await?;
+        Ok(tag[0])
+    }
+
+    async fn read_message(&mut self) -> Result<Bytes, RecvError> {
+        let mut len = [0u8; 4];
+        self.read_exact(&mut len).await?;
+        let len = u32::from_be_bytes(len) as usize;
+        let max = self.max_message_size;
+        ensure!(len <= max, recv_error::TooLargeSnafu { len, max });
+        let mut buf = BytesMut::zeroed(len);
+        self.read_exact(&mut buf).await?;
+        Ok(buf.freeze())
+    }
+
+    /// Fills `buf`, failing with [`RecvError::UnexpectedEnd`] if the stream finishes first.
+    async fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), RecvError> {
+        match self.recv.read_exact(buf).await {
+            Ok(()) => Ok(()),
+            Err(ReadExactError::FinishedEarly(_)) => Err(recv_error::UnexpectedEndSnafu.build()),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+/// A frame read by a [`MessageReceiver`].
+#[derive(Debug)]
+enum Frame {
+    Message(Bytes),
+    Heartbeat,
+    Close,
+}
+
+#[cfg(test)]
+mod tests {
+    use n0_snafu::{Result, ResultExt};
+    use n0_watcher::Watcher;
+
+    use super::*;
+    use crate::{endpoint::Connection, Endpoint, RelayMode};
+
+    const TEST_ALPN: &[u8] = b"n0/iroh/test/proto-util";
+
+    /// Connects two endpoints and returns both ends of a single bi-directional stream.
+    async fn stream_pair() -> Result<(
+        (Endpoint, Endpoint, Connection, Connection),
+        (SendStream, RecvStream),
+        (SendStream, RecvStream),
+    )> {
+        let ep1 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .alpns(vec![TEST_ALPN.to_vec()])
+            .bind()
+            .await?;
+        let ep2 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let addr1 = ep1.node_addr().initialized().await?;
+
+        let accept = tokio::spawn({
+            let ep1 = ep1.clone();
+            async move {
+                let conn = ep1.accept().await.e()?.await.e()?;
+                let (send, recv) = conn.accept_bi().await.e()?;
+                Ok::<_, n0_snafu::Error>((conn, send, recv))
+            }
+        });
+        let conn = ep2.connect(addr1, TEST_ALPN).await?;
+        let (mut send, recv) = conn.open_bi().await.e()?;
+        // Streams are only announced to the peer once data is written.
+        send.write_all(&[FRAME_HEARTBEAT]).await.e()?;
+        let (server_conn, server_send, server_recv) = accept.await.e()??;
+        Ok((
+            (ep1, ep2, conn, server_conn),
+            (send, recv),
+            (server_send, server_recv),
+        ))
+    }
+
+    #[tokio::test]
+    async fn test_messages_and_close() -> Result {
+        let (_guard, (send, _recv), (_server_send, server_recv)) = stream_pair().await?;
+        let mut sender = MessageSender::new(send);
+        let mut receiver = MessageReceiver::new(server_recv);
+
+        sender.send(b"hello").await?;
+        sender.heartbeat().await?;
+        sender.send(b"").await?;
+        sender.send(b"world").await?;
+        sender.close().await?;
+
+        assert_eq!(receiver.recv().await?.as_deref(), Some(&b"hello"[..]));
+        assert_eq!(receiver.recv().await?.as_deref(), Some(&b""[..]));
+        assert_eq!(receiver.recv().await?.as_deref(), Some(&b"world"[..]));
+        assert_eq!(receiver.recv().await?, None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_too_large() -> Result {
+        let (_guard, (send, _recv), (_server_send, server_recv)) = stream_pair().await?;
+        let mut sender = MessageSender::new(send);
+        let mut receiver = MessageReceiver::new(server_recv).max_message_size(4);
+
+        sender.send(b"hello").await?;
+        let err = receiver.recv().await.unwrap_err();
+        assert!(matches!(err, RecvError::TooLarge { len: 5, max: 4, .. }));
+
+        // The rest of the frame is not decoded as the next frame.
+        sender.send(b"").await.ok();
+        let err = receiver.recv().await.unwrap_err();
+        assert!(matches!(err, RecvError::Read { .. }));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unexpected_end() -> Result {
+        let (_guard, (send, _recv), (_server_send, server_recv)) = stream_pair().await?;
+        let mut sender = MessageSender::new(send);
+        let mut receiver = MessageReceiver::new(server_recv);
+
+        sender.send(b"hello").await?;
+        sender.into_inner().finish().e()?;
+
+        assert_eq!(receiver.recv().await?.as_deref(), Some(&b"hello"[..]));
+        let err = receiver.recv().await.unwrap_err();
+        assert!(matches!(err, RecvError::UnexpectedEnd { .. }));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_truncated_frame() -> Result {
+        let (_guard, (mut send, _recv), (_server_send, server_recv)) = stream_pair().await?;
+        let mut receiver = MessageReceiver::new(server_recv);
+
+        // A message header announcing 5 bytes, followed by only 2 of them.
+        send.write_all(&[FRAME_MESSAGE, 0, 0, 0, 5, 1, 2])
+            .await
+            .e()?;
+        send.finish().e()?;
+        let err = receiver.recv().await.unwrap_err();
+        assert!(matches!(err, RecvError::UnexpectedEnd { .. }));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() -> Result {
+        let (_guard, (_send, _recv), (_server_send, server_recv)) = stream_pair().await?;
+        let mut receiver =
+            MessageReceiver::new(server_recv).idle_timeout(Duration::from_millis(100));
+
+        let err = receiver.recv().await.unwrap_err();
+        assert!(matches!(err, RecvError::IdleTimeout { .. }));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout_partial_frame() -> Result {
+        let (_guard, (mut send, _recv), (_server_send, server_recv)) = stream_pair().await?;
+        let mut receiver =
+            MessageReceiver::new(server_recv).idle_timeout(Duration::from_millis(100));
+
+        // A message header announcing 5 bytes, which never arrive.
+        send.write_all(&[FRAME_MESSAGE, 0, 0, 0, 5]).await.e()?;
+        let err = receiver.recv().await.unwrap_err();
+        assert!(matches!(err, RecvError::IdleTimeout { .. }));
+        Ok(())
+    }
+}
@@ -32,23 +32,36 @@
 //!     }
 //! }
 //! ```
-use std::{collections::BTreeMap, future::Future, pin::Pin, sync::Arc};
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    net::IpAddr,
+    pin::Pin,
+    sync::Arc,
+};
 
+use backon::{Backoff, BackoffBuilder, ExponentialBuilder};
 use iroh_base::NodeId;
+use iroh_metrics::{Counter, MetricsGroup};
 use n0_future::{
     join_all,
     task::{self, AbortOnDropHandle, JoinSet},
+    time::{self, Duration, Instant},
 };
+use serde::{Deserialize, Serialize};
 use snafu::{Backtrace, Snafu};
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, OwnedSemaphorePermit, Semaphore};
 use tokio_util::sync::CancellationToken;
-use tracing::{error, info_span, trace, warn, Instrument};
+use tracing::{debug, error, info_span, trace, warn, Instrument};
 
 use crate::{
-    endpoint::{Connecting, Connection, RemoteNodeIdError},
+    endpoint::{Connecting, Connection, ConnectionStats, Incoming, RemoteNodeIdError},
     Endpoint,
 };
 
+pub mod attestation;
+pub mod ping;
+
 /// The built router.
 ///
 /// Construct this using [`Router::builder`].
@@ -85,13 +98,176 @@ pub struct Router {
     // `Router` needs to be `Clone + Send`, and we need to `task.await` in its `shutdown()` impl.
     task: Arc<Mutex<Option<AbortOnDropHandle<()>>>>,
     cancel_token: CancellationToken,
+    protocols: Arc<ProtocolMap>,
+    metrics: Arc<Metrics>,
+    protocol_stats: ProtocolStatsMap,
+    health: Arc<std::sync::Mutex<RouterHealth>>,
 }
 
 /// Builder for creating a [`Router`] for accepting protocols.
-#[derive(Debug)]
+#[derive(derive_more::Debug)]
 pub struct RouterBuilder {
     endpoint: Endpoint,
     protocols: ProtocolMap,
+    handshake_rate_limit: Option<HandshakeRateLimit>,
+    global_handshake_rate_limit: Option<HandshakeRateLimit>,
+    max_pending_handshakes: Option<usize>,
+    max_connections: Option<usize>,
+    max_connections_per_alpn: BTreeMap<Vec<u8>, usize>,
+    drain_timeout: Option<Duration>,
+    isolate_handler_panics: bool,
+    restart_policy: Option<RestartPolicy>,
+    #[debug("{} middleware", middleware.len())]
+    middleware: Vec<Box<MiddlewareFn>>,
+    #[cfg(feature = "metrics-server")]
+    metrics_addr: Option<std::net::SocketAddr>,
+}
+
+/// Metrics collected by a [`Router`].
+#[derive(Debug, Default, Serialize, Deserialize, MetricsGroup)]
+#[non_exhaustive]
+#[metrics(name = "router")]
+pub struct Metrics {
+    /// Number of incoming connections handed to the protocol handlers.
+    pub incoming_accepted: Counter,
+    /// Number of incoming connections refused because of the [`HandshakeRateLimit`].
+    pub incoming_rate_limited: Counter,
+    /// Number of incoming connections rejected by a connection middleware.
+    ///
+    /// See [`RouterBuilder::connection_middleware`].
+    pub incoming_rejected: Counter,
+    /// Number of incoming connections refused because the connection limit was reached.
+    ///
+    /// See [`RouterBuilder::max_connections`], [`RouterBuilder::max_connections_for_alpn`]
+    /// and [`RouterBuilder::max_pending_handshakes`].
+    pub incoming_connection_limited: Counter,
+    /// Number of connections for which a [`ProtocolHandler`] returned an error.
+    pub handler_errors: Counter,
+    /// Number of connections for which a [`ProtocolHandler`] panicked.
+    pub handler_panics: Counter,
+}
+
+/// The health of a [`Router`].
+///
+/// See [`Router::health`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct RouterHealth {
+    /// Whether the accept loop is running.
+    pub running: bool,
+    /// Why the accept loop stopped, `None` while it is running.
+    pub exit_reason: Option<RouterExitReason>,
+    /// Number of connections for which a [`ProtocolHandler`] panicked.
+    pub handler_panics: u64,
+    /// The most recent panic of a [`ProtocolHandler`].
+    pub last_panic: Option<String>,
+    /// Number of times the accept loop paused accepting after a [`ProtocolHandler`] panicked.
+    ///
+    /// See [`RouterBuilder::restart_on_handler_panic`].
+    pub restarts: u64,
+}
+
+/// Why the accept loop of a [`Router`] stopped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum RouterExitReason {
+    /// [`Router::shutdown`] was called, or the router was dropped.
+    Shutdown,
+    /// The [`Endpoint`] was closed.
+    EndpointClosed,
+    /// A [`ProtocolHandler`] panicked.
+    ///
+    /// See [`RouterBuilder::isolate_handler_panics`] and
+    /// [`RouterBuilder::restart_on_handler_panic`].
+    HandlerPanicked,
+}
+
+/// Statistics about the connections handled by the [`ProtocolHandler`] of a single ALPN.
+///
+/// See [`Router::protocol_stats`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ProtocolStats {
+    /// Number of connections handed to the protocol handler.
+    pub accepted: u64,
+    /// Number of connections currently handled by the protocol handler.
+    pub active: u64,
+    /// Number of connections for which the protocol handler returned an error.
+    pub errors: u64,
+    /// Number of UDP bytes sent on completed connections.
+    ///
+    /// This includes the QUIC overhead, and connections are only counted once the
+    /// protocol handler is done with them.
+    pub bytes_sent: u64,
+    /// Number of UDP bytes received on completed connections.
+    ///
+    /// This includes the QUIC overhead, and connections are only counted once the
+    /// protocol handler is done with them.
+    pub bytes_received: u64,
+    /// Number of completed connections by how long the protocol handler took to handle them.
+    ///
+    /// The entry at index `i` counts the connections handled within
+    /// [`ProtocolStats::LATENCY_BUCKETS`]`[i]`, and not within any shorter bucket.  The last
+    /// entry counts the connections which took longer than all buckets.
+    pub latency_histogram: [u64; ProtocolStats::LATENCY_BUCKETS.len() + 1],
+}
+
+impl ProtocolStats {
+    /// The upper bounds of the buckets of [`ProtocolStats::latency_histogram`].
+    pub const LATENCY_BUCKETS: [Duration; 5] = [
+        Duration::from_millis(10),
+        Duration::from_millis(100),
+        Duration::from_secs(1),
+        Duration::from_secs(10),
+        Duration::from_secs(60),
+    ];
+
+    fn record(&mut self, latency: Duration, is_err: bool, stats: Option<ConnectionStats>) {
+        if is_err {
+            self.errors += 1;
+        }
+        if let Some(stats) = stats {
+            self.bytes_sent += stats.udp_tx.bytes;
+            self.bytes_received += stats.udp_rx.bytes;
+        }
+        let bucket = Self::LATENCY_BUCKETS
+            .iter()
+            .position(|bound| latency <= *bound)
+            .unwrap_or(Self::LATENCY_BUCKETS.len());
+        self.latency_histogram[bucket] += 1;
+    }
+}
+
+type ProtocolStatsMap = Arc<std::sync::Mutex<BTreeMap<Vec<u8>, ProtocolStats>>>;
+
+/// Counts a connection as [`ProtocolStats::active`] while alive.
+struct ActiveConnection {
+    protocol_stats: ProtocolStatsMap,
+    alpn: Vec<u8>,
+}
+
+impl ActiveConnection {
+    fn new(protocol_stats: ProtocolStatsMap, alpn: Vec<u8>) -> Self {
+        {
+            let mut stats = protocol_stats.lock().expect("poisoned");
+            let stats = stats.entry(alpn.clone()).or_default();
+            stats.accepted += 1;
+            stats.active += 1;
+        }
+        Self {
+            protocol_stats,
+            alpn,
+        }
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        let mut stats = self.protocol_stats.lock().expect("poisoned");
+        if let Some(stats) = stats.get_mut(&self.alpn) {
+            stats.active -= 1;
+        }
+    }
 }
 
 #[allow(missing_docs)]
@@ -145,7 +321,7 @@ impl From<quinn::ClosedStream> for AcceptError {
 ///
 /// Implement this trait on a struct that should handle incoming connections.
 /// The protocol handler must then be registered on the node for an ALPN protocol with
-/// [`crate::protocol::RouterBuilder::accept`].
+/// [`crate::protocol::RouterBuilder::accept`] or [`Router::add_protocol`].
 ///
 /// See the [module documentation](crate::protocol) for an example.
 pub trait ProtocolHandler: Send + Sync + std::fmt::Debug + 'static {
@@ -267,31 +443,40 @@ impl<P: ProtocolHandler> DynProtocolHandler for P {
 
 /// A typed map of protocol handlers, mapping them from ALPNs.
 #[derive(Debug, Default)]
-pub(crate) struct ProtocolMap(BTreeMap<Vec<u8>, Box<dyn DynProtocolHandler>>);
+pub(crate) struct ProtocolMap(std::sync::RwLock<BTreeMap<Vec<u8>, Arc<dyn DynProtocolHandler>>>);
 
 impl ProtocolMap {
     /// Returns the registered protocol handler for an ALPN as a [`Arc<dyn ProtocolHandler>`].
-    pub(crate) fn get(&self, alpn: &[u8]) -> Option<&dyn DynProtocolHandler> {
-        self.0.get(alpn).map(|p| &**p)
+    pub(crate) fn get(&self, alpn: &[u8]) -> Option<Arc<dyn DynProtocolHandler>> {
+        self.0.read().expect("poisoned").get(alpn).cloned()
+    }
+
+    /// Inserts a protocol handler, returning the handler previously registered for the ALPN.
+    pub(crate) fn insert(
+        &self,
+        alpn: Vec<u8>,
+        handler: impl ProtocolHandler,
+    ) -> Option<Arc<dyn DynProtocolHandler>> {
+        let handler = Arc::new(handler);
+        self.0.write().expect("poisoned").insert(alpn, handler)
     }
 
-    /// Inserts a protocol handler.
-    pub(crate) fn insert(&mut self, alpn: Vec<u8>, handler: impl ProtocolHandler) {
-        let handler = Box::new(handler);
-        self.0.insert(alpn, handler);
+    /// Removes the protocol handler for an ALPN and returns it.
+    pub(crate) fn remove(&self, alpn: &[u8]) -> Option<Arc<dyn DynProtocolHandler>> {
+        self.0.write().expect("poisoned").remove(alpn)
     }
 
-    /// Returns an iterator of all registered ALPN protocol identifiers.
-    pub(crate) fn alpns(&self) -> impl Iterator<Item = &Vec<u8>> {
-        self.0.keys()
+    /// Returns all registered ALPN protocol identifiers.
+    pub(crate) fn alpns(&self) -> Vec<Vec<u8>> {
+        self.0.read().expect("poisoned").keys().cloned().collect()
     }
 
     /// Shuts down all protocol handlers.
     ///
     /// Calls and awaits [`ProtocolHandler::shutdown`] for all registered handlers concurrently.
     pub(crate) async fn shutdown(&self) {
-        let handlers = self.0.values().map(|p| p.shutdown());
-        join_all(handlers).await;
+        let handlers: Vec<_> = self.0.read().expect("poisoned").values().cloned().collect();
+        join_all(handlers.iter().map(|p| p.shutdown())).await;
     }
 }
 
@@ -306,6 +491,58 @@ impl Router {
         &self.endpoint
     }
 
+    /// Returns the metrics collected by this router.
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// Returns whether the accept loop is running, and why it stopped otherwise.
+    pub fn health(&self) -> RouterHealth {
+        self.health.lock().expect("poisoned").clone()
+    }
+
+    /// Returns the statistics of the connections handled, for each ALPN.
+    pub fn protocol_stats(&self) -> BTreeMap<Vec<u8>, ProtocolStats> {
+        self.protocol_stats.lock().expect("poisoned").clone()
+    }
+
+    /// Registers a [`ProtocolHandler`] for `alpn` on the running router.
+    ///
+    /// The endpoint starts accepting connections for `alpn` right away.  If a handler was
+    /// already registered for `alpn` it is replaced and [`ProtocolHandler::shutdown`] is
+    /// called on it, like for [`Router::remove_protocol`].  Connections it is already handling
+    /// are not affected.
+    pub async fn add_protocol(&self, alpn: impl AsRef<[u8]>, handler: impl ProtocolHandler) {
+        let replaced = self.protocols.insert(alpn.as_ref().to_vec(), handler);
+        self.update_alpns();
+        if let Some(handler) = replaced {
+            handler.shutdown().await;
+        }
+    }
+
+    /// Removes the [`ProtocolHandler`] for `alpn` from the running router.
+    ///
+    /// The endpoint stops accepting new connections for `alpn` and
+    /// [`ProtocolHandler::shutdown`] is called on the removed handler.  Connections it is
+    /// already handling are not affected.
+    ///
+    /// Returns `false` if no handler was registered for `alpn`.
+    pub async fn remove_protocol(&self, alpn: impl AsRef<[u8]>) -> bool {
+        let Some(handler) = self.protocols.remove(alpn.as_ref()) else {
+            return false;
+        };
+        self.update_alpns();
+        handler.shutdown().await;
+        true
+    }
+
+    /// Updates the endpoint's ALPNs to match the registered protocols.
+    fn update_alpns(&self) {
+        // Hold the lock so concurrent updates can not set an outdated list.
+        let protocols = self.protocols.0.read().expect("poisoned");
+        self.endpoint.set_alpns(protocols.keys().cloned().collect());
+    }
+
     /// Checks if the router is already shutdown.
     pub fn is_shutdown(&self) -> bool {
         self.cancel_token.is_cancelled()
@@ -316,6 +553,9 @@ impl Router {
     /// When this function returns, all [`ProtocolHandler`]s will be shutdown and
     /// `Endpoint::close` will have been called.
     ///
+    /// With a [`RouterBuilder::drain_timeout`], in-flight connections are given time to
+    /// complete first.
+    ///
     /// If already shutdown, it returns `Ok`.
     ///
     /// If some [`ProtocolHandler`] panicked in the accept loop, this will propagate
@@ -343,16 +583,157 @@ impl RouterBuilder {
         Self {
             endpoint,
             protocols: ProtocolMap::default(),
+            handshake_rate_limit: None,
+            global_handshake_rate_limit: None,
+            max_pending_handshakes: None,
+            max_connections: None,
+            max_connections_per_alpn: BTreeMap::new(),
+            drain_timeout: None,
+            isolate_handler_panics: false,
+            restart_policy: None,
+            middleware: Vec::new(),
+            #[cfg(feature = "metrics-server")]
+            metrics_addr: None,
         }
     }
 
     /// Configures the router to accept the [`ProtocolHandler`] when receiving a connection
     /// with this `alpn`.
-    pub fn accept(mut self, alpn: impl AsRef<[u8]>, handler: impl ProtocolHandler) -> Self {
+    pub fn accept(self, alpn: impl AsRef<[u8]>, handler: impl ProtocolHandler) -> Self {
         self.protocols.insert(alpn.as_ref().to_vec(), handler);
         self
     }
 
+    /// Limits the rate at which incoming connections are accepted from each remote IP
+    /// address or prefix.
+    ///
+    /// Incoming connections exceeding the limit are refused before their handshake is
+    /// processed or any [`ProtocolHandler`] is invoked.  Connections refused by any other
+    /// limit before their handshake do not count against this limit, connections closed by
+    /// [`RouterBuilder::max_connections_for_alpn`] after their handshake do.  By default
+    /// there is no limit.
+    pub fn handshake_rate_limit(mut self, limit: HandshakeRateLimit) -> Self {
+        self.handshake_rate_limit = Some(limit);
+        self
+    }
+
+    /// Limits the rate at which incoming connections are accepted from all remotes together.
+    ///
+    /// Unlike [`RouterBuilder::handshake_rate_limit`] this applies a single token bucket to
+    /// all incoming connections, protecting against floods from many addresses.  Incoming
+    /// connections exceeding the limit are refused before their handshake is processed.
+    /// Connections refused by any other limit before their handshake do not count against
+    /// this limit, connections closed by [`RouterBuilder::max_connections_for_alpn`] after
+    /// their handshake do.  By default there is no limit.
+    pub fn global_handshake_rate_limit(mut self, limit: HandshakeRateLimit) -> Self {
+        self.global_handshake_rate_limit = Some(limit);
+        self
+    }
+
+    /// Limits the number of handshakes processed concurrently.
+    ///
+    /// A connection counts against the limit until its handshake completed, i.e. until
+    /// [`ProtocolHandler::on_connecting`] returned.  Incoming connections exceeding the
+    /// limit are refused before their handshake is processed.  By default there is no
+    /// limit.
+    ///
+    /// Values larger than [`Semaphore::MAX_PERMITS`] are treated as [`Semaphore::MAX_PERMITS`].
+    pub fn max_pending_handshakes(mut self, max: usize) -> Self {
+        self.max_pending_handshakes = Some(max.min(Semaphore::MAX_PERMITS));
+        self
+    }
+
+    /// Limits the number of connections handled concurrently.
+    ///
+    /// A connection counts against the limit from when it is accepted until its
+    /// [`ProtocolHandler::accept`] future completed, including its handshake.  Incoming
+    /// connections exceeding the limit are refused before their handshake is processed.  By
+    /// default there is no limit.
+    ///
+    /// Values larger than [`Semaphore::MAX_PERMITS`] are treated as [`Semaphore::MAX_PERMITS`].
+    pub fn max_connections(mut self, max: usize) -> Self {
+        self.max_connections = Some(max.min(Semaphore::MAX_PERMITS));
+        self
+    }
+
+    /// Limits the number of connections handled concurrently for a single `alpn`.
+    ///
+    /// A connection counts against the limit from when its ALPN is known, early in the
+    /// handshake, until its [`ProtocolHandler::accept`] future completed.  Connections
+    /// exceeding the limit are closed once their handshake completed, they still count against
+    /// the handshake rate limits.  By default there is no limit.
+    ///
+    /// Values larger than [`Semaphore::MAX_PERMITS`] are treated as [`Semaphore::MAX_PERMITS`].
+    pub fn max_connections_for_alpn(mut self, alpn: impl AsRef<[u8]>, max: usize) -> Self {
+        self.max_connections_per_alpn
+            .insert(alpn.as_ref().to_vec(), max.min(Semaphore::MAX_PERMITS));
+        self
+    }
+
+    /// Drains in-flight connections when shutting down, for up to `timeout`.
+    ///
+    /// On [`Router::shutdown`] the router stops accepting new connections, and then waits
+    /// up to `timeout` for the running [`ProtocolHandler::accept`] futures to complete
+    /// before it shuts down the protocol handlers and closes the endpoint.  By default the
+    /// router does not wait.
+    pub fn drain_timeout(mut self, timeout: Duration) -> Self {
+        self.drain_timeout = Some(timeout);
+        self
+    }
+
+    /// Serves the metrics of the router and its endpoint over HTTP on `addr`.
+    ///
+    /// The metrics are served in the OpenMetrics text format on the `/metrics` path, for
+    /// scraping by Prometheus.  The server runs until the router is shut down.
+    #[cfg(feature = "metrics-server")]
+    pub fn metrics_addr(mut self, addr: std::net::SocketAddr) -> Self {
+        self.metrics_addr = Some(addr);
+        self
+    }
+
+    /// Keeps the router running when a [`ProtocolHandler`] panics.
+    ///
+    /// By default a panic in [`ProtocolHandler::accept`] shuts down the router.  With this
+    /// enabled only the connection the handler panicked on is dropped, and the panic is
+    /// recorded in [`Router::health`].
+    pub fn isolate_handler_panics(mut self, isolate: bool) -> Self {
+        self.isolate_handler_panics = isolate;
+        self
+    }
+
+    /// Pauses the accept loop with a backoff when a [`ProtocolHandler`] panics.
+    ///
+    /// By default a panic in [`ProtocolHandler::accept`] shuts down the router.  With a
+    /// [`RestartPolicy`] the router instead stops accepting new connections for a delay
+    /// which grows with each consecutive panic, and then resumes accepting in the same
+    /// accept loop; the loop task and handler state are not recreated.  Connections which are
+    /// already being handled are not affected.  Once the policy's retries are exhausted the
+    /// router shuts down.  The delay is reset once a connection was handled without panics.
+    ///
+    /// Has no effect if [`RouterBuilder::isolate_handler_panics`] is enabled.
+    pub fn restart_on_handler_panic(mut self, policy: RestartPolicy) -> Self {
+        self.restart_policy = Some(policy);
+        self
+    }
+
+    /// Adds a middleware which runs for every incoming connection.
+    ///
+    /// The middleware is called with the established connection, its remote [`NodeId`]
+    /// and its ALPN before the connection is passed to [`ProtocolHandler::accept`].  It can
+    /// log the connection, attach values for the protocol handlers using
+    /// [`Connection::set_context`], and reject the connection by returning `false`.
+    /// Rejected connections are closed with an error code of `0` and reason `not allowed`.
+    ///
+    /// Middleware runs in the order it was added, until one rejects the connection.  To
+    /// limit the access to a single protocol use [`AccessLimit`] instead.
+    pub fn connection_middleware<F>(mut self, middleware: F) -> Self
+    where
+        F: Fn(&Connection, NodeId, &[u8]) -> bool + Send + Sync + 'static,
+    {
+        self.middleware.push(Box::new(middleware));
+        self
+    }
+
     /// Returns the [`Endpoint`] of the node.
     pub fn endpoint(&self) -> &Endpoint {
         &self.endpoint
@@ -361,34 +742,85 @@ impl RouterBuilder {
     /// Spawns an accept loop and returns a handle to it encapsulated as the [`Router`].
     pub fn spawn(self) -> Router {
         // Update the endpoint with our alpns.
-        let alpns = self
-            .protocols
-            .alpns()
-            .map(|alpn| alpn.to_vec())
-            .collect::<Vec<_>>();
+        let alpns = self.protocols.alpns();
 
         let protocols = Arc::new(self.protocols);
         self.endpoint.set_alpns(alpns);
+        let router_protocols = protocols.clone();
 
         let mut join_set = JoinSet::new();
         let endpoint = self.endpoint.clone();
+        let mut rate_limiter = self.handshake_rate_limit.map(RateLimiter::new);
+        let mut global_rate_limiter = self.global_handshake_rate_limit.map(RateLimiter::new);
+        let handshake_limit = self
+            .max_pending_handshakes
+            .map(|max| Arc::new(Semaphore::new(max)));
+        let connection_limit = self
+            .max_connections
+            .map(|max| Arc::new(Semaphore::new(max)));
+        let alpn_limits: Arc<BTreeMap<_, _>> = Arc::new(
+            self.max_connections_per_alpn
+                .into_iter()
+                .map(|(alpn, max)| (alpn, Arc::new(Semaphore::new(max))))
+                .collect(),
+        );
+        let middleware: Arc<[Box<MiddlewareFn>]> = self.middleware.into();
 
         // Our own shutdown works with a cancellation token.
         let cancel = CancellationToken::new();
         let cancel_token = cancel.clone();
 
+        let metrics = Arc::new(Metrics::default());
+        let loop_metrics = metrics.clone();
+        let protocol_stats = ProtocolStatsMap::default();
+        let loop_protocol_stats = protocol_stats.clone();
+        let drain_timeout = self.drain_timeout;
+        let isolate_handler_panics = self.isolate_handler_panics;
+        let restart_policy = self.restart_policy;
+        let health = Arc::new(std::sync::Mutex::new(RouterHealth {
+            running: true,
+            ..Default::default()
+        }));
+        let loop_health = health.clone();
+
+        #[cfg(feature = "metrics-server")]
+        let metrics_server = self.metrics_addr.map(|addr| {
+            let mut registry = iroh_metrics::Registry::default();
+            registry.register(metrics.clone());
+            registry.register_all(self.endpoint.metrics());
+            let registry = Arc::new(registry);
+            AbortOnDropHandle::new(task::spawn(
+                async move {
+                    if let Err(err) =
+                        iroh_metrics::service::start_metrics_server(addr, registry).await
+                    {
+                        warn!("Metrics server failed: {err:#}");
+                    }
+                }
+                .instrument(info_span!("router.metrics-server")),
+            ))
+        });
+
         let run_loop_fut = async move {
+            // Stop the metrics server together with the accept loop.
+            #[cfg(feature = "metrics-server")]
+            let _metrics_server = metrics_server;
             // Make sure to cancel the token, if this future ever exits.
             let _cancel_guard = cancel_token.clone().drop_guard();
             // We create a separate cancellation token to stop any `ProtocolHandler::accept` futures
             // that are still running after `ProtocolHandler::shutdown` was called.
             let handler_cancel_token = CancellationToken::new();
 
-            loop {
+            // Backoff for pauses after handler panics, and when to resume accepting.
+            let mut restart_backoff = restart_policy.map(|policy| policy.backoff());
+            let mut backoff_used = false;
+            let mut paused_until: Option<Instant> = None;
+
+            let exit_reason = loop {
                 tokio::select! {
                     biased;
                     _ = cancel_token.cancelled() => {
-                        break;
+                        break RouterExitReason::Shutdown;
                     },
                     // handle task terminations and quit on panics.
                     Some(res) = join_set.join_next() => {
@@ -396,16 +828,40 @@ impl RouterBuilder {
                             Err(outer) => {
                                 if outer.is_panic() {
                                     error!("Task panicked: {outer:?}");
-                                    break;
+                                    loop_metrics.handler_panics.inc();
+                                    let restart_delay = restart_backoff
+                                        .as_mut()
+                                        .filter(|_| !isolate_handler_panics)
+                                        .and_then(|backoff| backoff.next());
+                                    {
+                                        let mut health = loop_health.lock().expect("poisoned");
+                                        health.handler_panics += 1;
+                                        health.last_panic = Some(outer.to_string());
+                                        if restart_delay.is_some() {
+                                            health.restarts += 1;
+                                        }
+                                    }
+                                    if !isolate_handler_panics {
+                                        let Some(delay) = restart_delay else {
+                                            break RouterExitReason::HandlerPanicked;
+                                        };
+                                        warn!(?delay, "Pausing accept loop after handler panic");
+                                        backoff_used = true;
+                                        paused_until = Some(Instant::now() + delay);
+                                    }
                                 } else if outer.is_cancelled() {
                                     trace!("Task cancelled: {outer:?}");
                                 } else {
                                     error!("Task failed: {outer:?}");
-                                    break;
+                                    break RouterExitReason::HandlerPanicked;
                                 }
                             }
                             Ok(Some(())) => {
                                 trace!("Task finished");
+                                if backoff_used {
+                                    restart_backoff = restart_policy.map(|policy| policy.backoff());
+                                    backoff_used = false;
+                                }
                             }
                             Ok(None) => {
                                 trace!("Task cancelled");
@@ -413,19 +869,113 @@ impl RouterBuilder {
                         }
                     },
 
+                    // resume accepting after a pause.
+                    _ = time::sleep_until(paused_until.unwrap_or_else(Instant::now)), if paused_until.is_some() => {
+                        debug!("Resuming accept loop");
+                        paused_until = None;
+                    },
+
                     // handle incoming p2p connections.
-                    incoming = endpoint.accept() => {
+                    incoming = endpoint.accept(), if paused_until.is_none() => {
                         let Some(incoming) = incoming else {
-                            break; // Endpoint is closed.
+                            break RouterExitReason::EndpointClosed;
+                        };
+                        // Limits which refuse a connection return the tokens it took from
+                        // the rate limits, so that refusals by one limit do not count
+                        // against the others.
+                        let now = Instant::now();
+                        let remote_key = match rate_limiter {
+                            Some(ref mut rate_limiter) => {
+                                let key = rate_limiter.limit.remote_key(&incoming);
+                                if !rate_limiter.check(key, now) {
+                                    debug!(remote = %key, "Refusing connection: handshake rate limit exceeded");
+                                    loop_metrics.incoming_rate_limited.inc();
+                                    incoming.refuse();
+                                    continue;
+                                }
+                                Some(key)
+                            }
+                            None => None,
+                        };
+                        if let Some(ref mut limiter) = global_rate_limiter {
+                            if !limiter.check((), now) {
+                                debug!("Refusing connection: global handshake rate limit exceeded");
+                                loop_metrics.incoming_rate_limited.inc();
+                                refund_handshake(&mut rate_limiter, &mut global_rate_limiter, remote_key, false);
+                                incoming.refuse();
+                                continue;
+                            }
+                        }
+                        let handshake_permit = match handshake_limit {
+                            Some(ref limit) => match limit.clone().try_acquire_owned() {
+                                Ok(permit) => Some(permit),
+                                Err(_) => {
+                                    debug!("Refusing connection: too many pending handshakes");
+                                    loop_metrics.incoming_connection_limited.inc();
+                                    refund_handshake(&mut rate_limiter, &mut global_rate_limiter, remote_key, true);
+                                    incoming.refuse();
+                                    continue;
+                                }
+                            },
+                            None => None,
+                        };
+                        let permit = match connection_limit {
+                            Some(ref limit) => match limit.clone().try_acquire_owned() {
+                                Ok(permit) => Some(permit),
+                                Err(_) => {
+                                    debug!("Refusing connection: connection limit reached");
+                                    loop_metrics.incoming_connection_limited.inc();
+                                    refund_handshake(&mut rate_limiter, &mut global_rate_limiter, remote_key, true);
+                                    incoming.refuse();
+                                    continue;
+                                }
+                            },
+                            None => None,
                         };
+                        loop_metrics.incoming_accepted.inc();
 
                         let protocols = protocols.clone();
+                        let alpn_limits = alpn_limits.clone();
+                        let middleware = middleware.clone();
+                        let metrics = loop_metrics.clone();
+                        let protocol_stats = loop_protocol_stats.clone();
                         let token = handler_cancel_token.child_token();
                         join_set.spawn(async move {
-                            token.run_until_cancelled(handle_connection(incoming, protocols)).await
+                            let _permit = permit;
+                            let fut = handle_connection(incoming, handshake_permit, protocols, alpn_limits, middleware, metrics, protocol_stats);
+                            token.run_until_cancelled(fut).await
                         }.instrument(info_span!("router.accept")));
                     },
                 }
+            };
+            debug!(?exit_reason, "Accept loop stopped");
+            {
+                let mut health = loop_health.lock().expect("poisoned");
+                health.running = false;
+                health.exit_reason = Some(exit_reason);
+            }
+
+            // If configured, we wait for the in-flight connections to finish while refusing new ones.
+            if let Some(timeout) = drain_timeout {
+                debug!("Draining connections");
+                let drain = async {
+                    loop {
+                        tokio::select! {
+                            res = join_set.join_next() => match res {
+                                None => break,
+                                Some(Err(err)) if err.is_panic() => error!("Task panicked: {err:?}"),
+                                Some(_) => {}
+                            },
+                            Some(incoming) = endpoint.accept() => {
+                                debug!("Refusing connection: draining");
+                                incoming.refuse();
+                            }
+                        }
+                    }
+                };
+                if time::timeout(timeout, drain).await.is_err() {
+                    debug!("Draining connections timed out");
+                }
             }
 
             // We first shutdown the protocol handlers to give them a chance to close connections gracefully.
@@ -452,11 +1002,41 @@ impl RouterBuilder {
             endpoint: self.endpoint,
             task: Arc::new(Mutex::new(Some(task))),
             cancel_token: cancel,
+            protocols: router_protocols,
+            metrics,
+            protocol_stats,
+            health,
         }
     }
 }
 
-async fn handle_connection(incoming: crate::endpoint::Incoming, protocols: Arc<ProtocolMap>) {
+/// Returns the handshake rate limit tokens taken for a connection refused by another limit.
+///
+/// The global token is only returned if `with_global` is set.
+fn refund_handshake(
+    rate_limiter: &mut Option<RateLimiter>,
+    global_rate_limiter: &mut Option<RateLimiter<()>>,
+    remote_key: Option<IpAddr>,
+    with_global: bool,
+) {
+    if let (Some(limiter), Some(key)) = (rate_limiter, remote_key) {
+        limiter.refund(&key);
+    }
+    if let (Some(limiter), true) = (global_rate_limiter, with_global) {
+        limiter.refund(&());
+    }
+}
+
+/// Handles an incoming connection.
+async fn handle_connection(
+    incoming: crate::endpoint::Incoming,
+    handshake_permit: Option<OwnedSemaphorePermit>,
+    protocols: Arc<ProtocolMap>,
+    alpn_limits: Arc<BTreeMap<Vec<u8>, Arc<Semaphore>>>,
+    middleware: Arc<[Box<MiddlewareFn>]>,
+    metrics: Arc<Metrics>,
+    protocol_stats: ProtocolStatsMap,
+) {
     let mut connecting = match incoming.accept() {
         Ok(conn) => conn,
         Err(err) => {
@@ -475,14 +1055,322 @@ async fn handle_connection(incoming: crate::endpoint::Incoming, protocols: Arc<P
         warn!("Ignoring connection: unsupported ALPN protocol");
         return;
     };
-    match handler.on_connecting(connecting).await {
-        Ok(connection) => {
-            if let Err(err) = handler.accept(connection).await {
+    let _permit = match alpn_limits.get(&alpn) {
+        Some(limit) => match limit.clone().try_acquire_owned() {
+            Ok(permit) => Some(permit),
+            Err(_) => {
+                // The handshake completes before the connection is closed, so its rate limit
+                // tokens are not returned.
+                debug!("Closing connection: connection limit for ALPN reached");
+                metrics.incoming_connection_limited.inc();
+                if let Ok(conn) = connecting.await {
+                    conn.close(0u32.into(), b"connection limit reached");
+                }
+                return;
+            }
+        },
+        None => None,
+    };
+    let active = ActiveConnection::new(protocol_stats.clone(), alpn.clone());
+    let start = Instant::now();
+    let mut connection_stats = None;
+    let connecting = handler.on_connecting(connecting).await;
+    drop(handshake_permit);
+    let is_err = match connecting {
+        Ok(connection) => match run_middleware(&middleware, &connection, &alpn) {
+            Ok(true) => {
+                let conn = connection.clone();
+                let res = handler.accept(connection).await;
+                connection_stats = Some(conn.stats());
+                match res {
+                    Ok(()) => false,
+                    Err(err) => {
+                        warn!("Handling incoming connection ended with error: {err}");
+                        true
+                    }
+                }
+            }
+            Ok(false) => {
+                debug!("Closing connection: rejected by middleware");
+                metrics.incoming_rejected.inc();
+                connection.close(0u32.into(), b"not allowed");
+                false
+            }
+            Err(err) => {
                 warn!("Handling incoming connection ended with error: {err}");
+                true
             }
-        }
+        },
         Err(err) => {
             warn!("Handling incoming connecting ended with error: {err}");
+            true
+        }
+    };
+    if is_err {
+        metrics.handler_errors.inc();
+    }
+    drop(active);
+    protocol_stats
+        .lock()
+        .expect("poisoned")
+        .entry(alpn)
+        .or_default()
+        .record(start.elapsed(), is_err, connection_stats);
+}
+
+type MiddlewareFn = dyn Fn(&Connection, NodeId, &[u8]) -> bool + Send + Sync + 'static;
+
+/// Runs the middleware, returns whether the connection is allowed.
+fn run_middleware(
+    middleware: &[Box<MiddlewareFn>],
+    connection: &Connection,
+    alpn: &[u8],
+) -> Result<bool, RemoteNodeIdError> {
+    if middleware.is_empty() {
+        return Ok(true);
+    }
+    let remote = connection.remote_node_id()?;
+    Ok(middleware.iter().all(|f| f(connection, remote, alpn)))
+}
+
+/// A token bucket limit on the rate of incoming connections from each remote.
+///
+/// Incoming connections are identified by the IP address they were received from, before
+/// their handshake is completed.  IPv4 addresses are grouped by their first 32 bits and
+/// IPv6 addresses by their first 64 bits by default, see
+/// [`HandshakeRateLimit::with_ipv4_prefix_len`] and
+/// [`HandshakeRateLimit::with_ipv6_prefix_len`].  Connections received via a relay server
+/// have no source IP address and are instead limited per remote node.
+///
+/// Each remote may open up to `burst` connections at once, after which the allowance is
+/// replenished by `per_second` connections every second.
+#[derive(Debug, Clone, Copy)]
+pub struct HandshakeRateLimit {
+    per_second: u32,
+    burst: u32,
+    ipv4_prefix_len: u8,
+    ipv6_prefix_len: u8,
+}
+
+impl HandshakeRateLimit {
+    /// Creates a new limit of `per_second` connections per second with bursts of `burst`.
+    ///
+    /// If `per_second` is zero the allowance is never replenished.  To bound memory use,
+    /// remotes which have not connected for [`HandshakeRateLimit::IDLE_TIMEOUT`] may
+    /// still be forgotten, after which they are allowed a new burst.
+    pub fn new(per_second: u32, burst: u32) -> Self {
+        Self {
+            per_second,
+            burst,
+            ipv4_prefix_len: 32,
+            ipv6_prefix_len: 64,
+        }
+    }
+
+    /// How long a remote has to be idle before it may be forgotten.
+    pub const IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+
+    /// The maximum number of remotes tracked at once.
+    ///
+    /// Once this many remotes are tracked, the least recently seen remotes are forgotten to
+    /// make room for new ones, after which they are allowed a new burst.
+    pub const MAX_REMOTES: usize = 65536;
+
+    /// Sets the number of leading bits by which IPv4 addresses are grouped.
+    ///
+    /// Values larger than 32 are treated as 32.
+    pub fn with_ipv4_prefix_len(mut self, len: u8) -> Self {
+        self.ipv4_prefix_len = len.min(32);
+        self
+    }
+
+    /// Sets the number of leading bits by which IPv6 addresses are grouped.
+    ///
+    /// Values larger than 128 are treated as 128.
+    pub fn with_ipv6_prefix_len(mut self, len: u8) -> Self {
+        self.ipv6_prefix_len = len.min(128);
+        self
+    }
+
+    /// Returns the key of the bucket limiting `incoming`.
+    fn remote_key(&self, incoming: &Incoming) -> IpAddr {
+        match incoming.remote_ip() {
+            Some(ip) => self.prefix(ip),
+            // The address used by QUIC is unique per remote node.
+            None => incoming.remote_address().ip(),
+        }
+    }
+
+    /// Masks `ip` to the configured prefix length.
+    fn prefix(&self, ip: IpAddr) -> IpAddr {
+        match ip.to_canonical() {
+            IpAddr::V4(ip) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.ipv4_prefix_len as u32)
+                    .unwrap_or(0);
+                IpAddr::from((u32::from(ip) & mask).to_be_bytes())
+            }
+            IpAddr::V6(ip) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.ipv6_prefix_len as u32)
+                    .unwrap_or(0);
+                IpAddr::from((u128::from(ip) & mask).to_be_bytes())
+            }
+        }
+    }
+}
+
+/// How long a [`Router`] pauses accepting after a [`ProtocolHandler`] panicked.
+///
+/// See [`RouterBuilder::restart_on_handler_panic`].
+#[derive(Debug, Clone, Copy)]
+pub struct RestartPolicy {
+    max_restarts: usize,
+    min_delay: Duration,
+    max_delay: Duration,
+}
+
+impl Default for RestartPolicy {
+    fn default() -> Self {
+        Self::new(5)
+    }
+}
+
+impl RestartPolicy {
+    /// Creates a policy which resumes accepting after up to `max_restarts` panics in a row.
+    ///
+    /// The pause starts at 100 milliseconds and doubles with each panic, up to 10 seconds.
+    pub fn new(max_restarts: usize) -> Self {
+        Self {
+            max_restarts,
+            min_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(10),
+        }
+    }
+
+    /// Sets the pause after the first panic.
+    pub fn with_min_delay(mut self, delay: Duration) -> Self {
+        self.min_delay = delay;
+        self
+    }
+
+    /// Sets the maximum pause after a panic.
+    pub fn with_max_delay(mut self, delay: Duration) -> Self {
+        self.max_delay = delay;
+        self
+    }
+
+    /// Returns the pauses before accepting resumes after each panic.
+    fn backoff(&self) -> impl Backoff {
+        ExponentialBuilder::new()
+            .with_min_delay(self.min_delay)
+            .with_max_delay(self.max_delay)
+            .with_max_times(self.max_restarts)
+            .build()
+    }
+}
+
+/// Keeps the token buckets of a [`HandshakeRateLimit`], one per key.
+#[derive(Debug)]
+struct RateLimiter<K = IpAddr> {
+    limit: HandshakeRateLimit,
+    buckets: HashMap<K, Bucket>,
+    last_prune: Instant,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl<K: std::hash::Hash + Eq + Clone> RateLimiter<K> {
+    /// The number of buckets at which we start removing unused buckets.
+    const PRUNE_THRESHOLD: usize = 4096;
+    /// The minimum time between removing unused buckets.
+    const PRUNE_INTERVAL: Duration = Duration::from_secs(1);
+
+    fn new(limit: HandshakeRateLimit) -> Self {
+        Self {
+            limit,
+            buckets: HashMap::new(),
+            last_prune: Instant::now(),
+        }
+    }
+
+    /// Takes a token for `key`, returns `false` if there was none left.
+    fn check(&mut self, key: K, now: Instant) -> bool {
+        if self.buckets.len() >= Self::PRUNE_THRESHOLD
+            && now.saturating_duration_since(self.last_prune) >= Self::PRUNE_INTERVAL
+        {
+            self.prune(now);
+        }
+        if self.buckets.len() >= HandshakeRateLimit::MAX_REMOTES && !self.buckets.contains_key(&key)
+        {
+            self.evict();
+        }
+        let HandshakeRateLimit {
+            per_second, burst, ..
+        } = self.limit;
+        let bucket = self.buckets.entry(key).or_insert(Bucket {
+            tokens: burst as f64,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated);
+        bucket.tokens =
+            (bucket.tokens + elapsed.as_secs_f64() * per_second as f64).min(burst as f64);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns a token taken by [`RateLimiter::check`] for `key`.
+    fn refund(&mut self, key: &K) {
+        let burst = self.limit.burst as f64;
+        if let Some(bucket) = self.buckets.get_mut(key) {
+            bucket.tokens = (bucket.tokens + 1.0).min(burst);
+        }
+    }
+
+    /// Removes all buckets which are full again, they are equivalent to new buckets, and
+    /// all buckets which were idle for [`HandshakeRateLimit::IDLE_TIMEOUT`].
+    fn prune(&mut self, now: Instant) {
+        let HandshakeRateLimit {
+            per_second, burst, ..
+        } = self.limit;
+        let refill = |bucket: &Bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated);
+            bucket.tokens + elapsed.as_secs_f64() * per_second as f64
+        };
+        let is_idle = |bucket: &Bucket| {
+            now.saturating_duration_since(bucket.updated) >= HandshakeRateLimit::IDLE_TIMEOUT
+        };
+        self.buckets
+            .retain(|_, bucket| refill(bucket) < burst as f64 && !is_idle(bucket));
+        self.last_prune = now;
+    }
+
+    /// Removes the least recently used buckets to make room for new ones.
+    ///
+    /// Exactly an eighth of the buckets is removed at once, so that a flood of new keys does
+    /// not scan all buckets for every key.  Buckets updated at the same time are removed
+    /// in no particular order.
+    fn evict(&mut self) {
+        let mut updated: Vec<(Instant, K)> = self
+            .buckets
+            .iter()
+            .map(|(key, bucket)| (bucket.updated, key.clone()))
+            .collect();
+        let n = (updated.len() / 8).max(1);
+        if n < updated.len() {
+            updated.select_nth_unstable_by_key(n - 1, |(updated, _)| *updated);
+        }
+        for (_, key) in updated.into_iter().take(n) {
+            self.buckets.remove(&key);
         }
     }
 }
@@ -495,9 +1383,11 @@ async fn handle_connection(incoming: crate::endpoint::Incoming, protocols: Arc<P
 pub struct AccessLimit<P: ProtocolHandler + Clone> {
     proto: P,
     #[debug("limiter")]
-    limiter: Arc<dyn Fn(NodeId) -> bool + Send + Sync + 'static>,
+    limiter: Arc<LimiterFn>,
 }
 
+type LimiterFn = dyn Fn(&Connection, NodeId) -> bool + Send + Sync + 'static;
+
 impl<P: ProtocolHandler + Clone> AccessLimit<P> {
     /// Create a new `AccessLimit`.
     ///
@@ -509,7 +1399,29 @@ impl<P: ProtocolHandler + Clone> AccessLimit<P> {
     {
         Self {
             proto,
-            limiter: Arc::new(limiter),
+            limiter: Arc::new(move |_conn, node_id| limiter(node_id)),
+        }
+    }
+
+    /// Create a new `AccessLimit` which attaches a context to allowed connections.
+    ///
+    /// The function should return the context for nodes that are allowed to connect, and
+    /// `None` otherwise.  The wrapped protocol retrieves the context using
+    /// [`Connection::context`].
+    pub fn with_context<F, T>(proto: P, limiter: F) -> Self
+    where
+        F: Fn(NodeId) -> Option<T> + Send + Sync + 'static,
+        T: Send + Sync + 'static,
+    {
+        Self {
+            proto,
+            limiter: Arc::new(move |conn, node_id| match limiter(node_id) {
+                Some(context) => {
+                    conn.set_context(context);
+                    true
+                }
+                None => false,
+            }),
         }
     }
 }
@@ -524,7 +1436,7 @@ impl<P: ProtocolHandler + Clone> ProtocolHandler for AccessLimit<P> {
 
     async fn accept(&self, conn: Connection) -> Result<(), AcceptError> {
         let remote = conn.remote_node_id()?;
-        let is_allowed = (self.limiter)(remote);
+        let is_allowed = (self.limiter)(&conn, remote);
         if !is_allowed {
             conn.close(0u32.into(), b"not allowed");
             return Err(NotAllowedSnafu.build());
@@ -540,7 +1452,13 @@ impl<P: ProtocolHandler + Clone> ProtocolHandler for AccessLimit<P> {
 
 #[cfg(test)]
 mod tests {
-    use std::{sync::Mutex, time::Duration};
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            Mutex,
+        },
+        time::Duration,
+    };
 
     use n0_snafu::{Result, ResultExt};
     use n0_watcher::Watcher;
@@ -617,6 +1535,796 @@ mod tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_limiter_context() -> Result {
+        #[derive(Debug, Clone)]
+        struct User(String);
+
+        #[derive(Debug, Clone)]
+        struct Greeter;
+
+        impl ProtocolHandler for Greeter {
+            async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+                let user = connection
+                    .context::<User>()
+                    .ok_or(NotAllowedSnafu.build())?;
+                let mut send = connection.open_uni().await?;
+                send.write_all(user.0.as_bytes())
+                    .await
+                    .map_err(AcceptError::from_err)?;
+                send.finish()?;
+                connection.closed().await;
+                Ok(())
+            }
+        }
+
+        let e1 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let e2 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let allowed = e2.node_id();
+        let proto = AccessLimit::with_context(Greeter, move |node_id| {
+            (node_id == allowed).then(|| User("alice".to_string()))
+        });
+        let r1 = Router::builder(e1).accept(ECHO_ALPN, proto).spawn();
+        let addr1 = r1.endpoint().node_addr().initialized().await?;
+
+        let conn = e2.connect(addr1.clone(), ECHO_ALPN).await?;
+        let mut recv = conn.accept_uni().await.e()?;
+        assert_eq!(recv.read_to_end(100).await.e()?, b"alice");
+        conn.close(0u32.into(), b"done");
+
+        let e3 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let conn = e3.connect(addr1, ECHO_ALPN).await?;
+        let err = conn.accept_uni().await.unwrap_err();
+        assert!(format!("{err:#?}").contains("not allowed"));
+
+        r1.shutdown().await.e()?;
+        e2.close().await;
+        e3.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_connection_middleware() -> Result {
+        const OTHER_ALPN: &[u8] = b"/iroh/other/1";
+
+        #[derive(Debug, Clone)]
+        struct Tag(&'static str);
+
+        #[derive(Debug, Clone)]
+        struct Tagged;
+
+        impl ProtocolHandler for Tagged {
+            async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+                let tag = connection.context::<Tag>().ok_or(NotAllowedSnafu.build())?;
+                let mut send = connection.open_uni().await?;
+                send.write_all(tag.0.as_bytes())
+                    .await
+                    .map_err(AcceptError::from_err)?;
+                send.finish()?;
+                connection.closed().await;
+                Ok(())
+            }
+        }
+
+        let e1 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let e2 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let allowed = e2.node_id();
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let r1 = Router::builder(e1)
+            .accept(ECHO_ALPN, Tagged)
+            .accept(OTHER_ALPN, Tagged)
+            .connection_middleware({
+                let seen = seen.clone();
+                move |_conn, node_id, alpn| {
+                    seen.lock().unwrap().push((node_id, alpn.to_vec()));
+                    true
+                }
+            })
+            .connection_middleware(move |conn, node_id, alpn| {
+                conn.set_context(Tag("tagged"));
+                node_id == allowed && alpn == ECHO_ALPN
+            })
+            .spawn();
+        let addr1 = r1.endpoint().node_addr().initialized().await?;
+
+        let conn = e2.connect(addr1.clone(), ECHO_ALPN).await?;
+        let mut recv = conn.accept_uni().await.e()?;
+        assert_eq!(recv.read_to_end(100).await.e()?, b"tagged");
+        conn.close(0u32.into(), b"done");
+
+        let conn = e2.connect(addr1, OTHER_ALPN).await?;
+        let err = conn.accept_uni().await.unwrap_err();
+        assert!(format!("{err:#?}").contains("not allowed"));
+
+        assert_eq!(
+            *seen.lock().unwrap(),
+            vec![
+                (allowed, ECHO_ALPN.to_vec()),
+                (allowed, OTHER_ALPN.to_vec())
+            ]
+        );
+        assert_eq!(r1.metrics().incoming_rejected.get(), 1);
+
+        r1.shutdown().await.e()?;
+        e2.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_protocol_stats() -> Result {
+        const DENIED_ALPN: &[u8] = b"/iroh/denied/1";
+        let e1 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let r1 = Router::builder(e1)
+            .accept(ECHO_ALPN, Echo)
+            .accept(DENIED_ALPN, AccessLimit::new(Echo, |_node_id| false))
+            .spawn();
+        let addr1 = r1.endpoint().node_addr().initialized().await?;
+
+        let e2 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let conn = e2.connect(addr1.clone(), ECHO_ALPN).await?;
+        let (mut send, mut recv) = conn.open_bi().await.e()?;
+        send.write_all(b"hello").await.e()?;
+        send.finish().e()?;
+        recv.read_to_end(100).await.e()?;
+        // The echo handler waits for the connection to be closed.
+        assert_eq!(r1.protocol_stats()[ECHO_ALPN].active, 1);
+        conn.close(0u32.into(), b"done");
+
+        let conn = e2.connect(addr1, DENIED_ALPN).await?;
+        conn.closed().await;
+
+        let stats = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                let stats = r1.protocol_stats();
+                let completed = |alpn: &[u8]| {
+                    stats
+                        .get(alpn)
+                        .is_some_and(|s| s.latency_histogram.iter().sum::<u64>() == 1)
+                };
+                if completed(ECHO_ALPN) && completed(DENIED_ALPN) {
+                    break stats;
+                }
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .e()?;
+        assert_eq!(stats[ECHO_ALPN].accepted, 1);
+        assert_eq!(stats[ECHO_ALPN].active, 0);
+        assert_eq!(stats[ECHO_ALPN].errors, 0);
+        assert!(stats[ECHO_ALPN].bytes_sent > 0);
+        assert!(stats[ECHO_ALPN].bytes_received > 0);
+        assert_eq!(stats[DENIED_ALPN].active, 0);
+        assert_eq!(stats[DENIED_ALPN].accepted, 1);
+        assert_eq!(stats[DENIED_ALPN].errors, 1);
+        assert_eq!(r1.metrics().handler_errors.get(), 1);
+
+        r1.shutdown().await.e()?;
+        e2.close().await;
+        Ok(())
+    }
+
+    #[test]
+    fn test_rate_limiter() {
+        let mut limiter = RateLimiter::new(HandshakeRateLimit::new(2, 3));
+        let a = IpAddr::from([10, 0, 0, 1]);
+        let b = IpAddr::from([10, 0, 0, 2]);
+        let now = Instant::now();
+
+        // The burst is available immediately, and per address.
+        for _ in 0..3 {
+            assert!(limiter.check(a, now));
+        }
+        assert!(!limiter.check(a, now));
+        assert!(limiter.check(b, now));
+
+        // Tokens are replenished over time.
+        let now = now + Duration::from_millis(500);
+        assert!(limiter.check(a, now));
+        assert!(!limiter.check(a, now));
+
+        // Refunded tokens are available again, up to the burst.
+        limiter.refund(&a);
+        assert!(limiter.check(a, now));
+        for _ in 0..5 {
+            limiter.refund(&b);
+        }
+        assert_eq!(limiter.buckets[&b].tokens, 3.0);
+
+        // Full buckets are pruned.
+        let now = now + Duration::from_secs(10);
+        limiter.prune(now);
+        assert!(limiter.buckets.is_empty());
+    }
+
+    #[test]
+    fn test_rate_limiter_idle() {
+        let mut limiter = RateLimiter::new(HandshakeRateLimit::new(0, 1));
+        let a = IpAddr::from([10, 0, 0, 1]);
+        let now = Instant::now();
+
+        // Without a rate the burst is not replenished.
+        assert!(limiter.check(a, now));
+        let now = now + Duration::from_secs(10);
+        assert!(!limiter.check(a, now));
+        limiter.prune(now);
+        assert_eq!(limiter.buckets.len(), 1);
+
+        // Idle buckets are pruned.
+        limiter.prune(now + HandshakeRateLimit::IDLE_TIMEOUT);
+        assert!(limiter.buckets.is_empty());
+    }
+
+    #[test]
+    fn test_rate_limiter_max_remotes() {
+        let mut limiter = RateLimiter::new(HandshakeRateLimit::new(0, 2));
+        let key = |i: u32| IpAddr::from(i.to_be_bytes());
+        let start = Instant::now();
+        let at = |i: u32| start + Duration::from_micros(i as u64);
+
+        let max = HandshakeRateLimit::MAX_REMOTES as u32;
+        for i in 0..max {
+            assert!(limiter.check(key(i), at(i)));
+        }
+        assert_eq!(limiter.buckets.len(), HandshakeRateLimit::MAX_REMOTES);
+
+        // The first remote connects again, and is now the most recently used one.
+        assert!(limiter.check(key(0), at(max)));
+
+        // New remotes are accepted, the least recently used remotes are forgotten.
+        let new = key(max);
+        assert!(limiter.check(new, at(max)));
+        assert!(limiter.buckets.len() < HandshakeRateLimit::MAX_REMOTES);
+        assert!(!limiter.buckets.contains_key(&key(1)));
+        assert!(limiter.buckets.contains_key(&key(max - 1)));
+
+        // Remotes which were not forgotten are still limited by their bucket.
+        assert!(!limiter.check(key(0), at(max)));
+    }
+
+    #[test]
+    fn test_rate_limiter_evict_same_timestamp() {
+        let mut limiter = RateLimiter::new(HandshakeRateLimit::new(0, 1));
+        let key = |i: u32| IpAddr::from(i.to_be_bytes());
+        let now = Instant::now();
+
+        let max = HandshakeRateLimit::MAX_REMOTES as u32;
+        for i in 0..max {
+            assert!(limiter.check(key(i), now));
+        }
+
+        // With all buckets updated at the same time only an eighth of them is forgotten.
+        assert!(limiter.check(key(max), now));
+        let evicted = HandshakeRateLimit::MAX_REMOTES / 8;
+        assert_eq!(
+            limiter.buckets.len(),
+            HandshakeRateLimit::MAX_REMOTES - evicted + 1
+        );
+        let limited = (0..max)
+            .filter(|&i| limiter.buckets.contains_key(&key(i)))
+            .filter(|&i| !limiter.check(key(i), now))
+            .count();
+        assert_eq!(limited, HandshakeRateLimit::MAX_REMOTES - evicted);
+    }
+
+    #[test]
+    fn test_rate_limit_prefix() {
+        let limit = HandshakeRateLimit::new(1, 1);
+        let v4: IpAddr = "192.0.2.42".parse().unwrap();
+        let v6: IpAddr = "2001:db8:1:2:3:4:5:6".parse().unwrap();
+        assert_eq!(limit.prefix(v4), v4);
+        assert_eq!(
+            limit.prefix(v6),
+            "2001:db8:1:2::".parse::<IpAddr>().unwrap()
+        );
+        let mapped: IpAddr = "::ffff:192.0.2.42".parse().unwrap();
+        assert_eq!(limit.prefix(mapped), v4);
+
+        let limit = limit.with_ipv4_prefix_len(24).with_ipv6_prefix_len(32);
+        assert_eq!(limit.prefix(v4), "192.0.2.0".parse::<IpAddr>().unwrap());
+        assert_eq!(limit.prefix(v6), "2001:db8::".parse::<IpAddr>().unwrap());
+
+        let limit = limit.with_ipv4_prefix_len(0).with_ipv6_prefix_len(200);
+        assert_eq!(limit.prefix(v4), "0.0.0.0".parse::<IpAddr>().unwrap());
+        assert_eq!(limit.prefix(v6), v6);
+    }
+
+    #[tokio::test]
+    async fn test_handshake_rate_limit() -> Result {
+        let e1 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let r1 = Router::builder(e1)
+            .accept(ECHO_ALPN, Echo)
+            .handshake_rate_limit(HandshakeRateLimit::new(1, 1))
+            .spawn();
+        let addr1 = r1.endpoint().node_addr().initialized().await?;
+
+        let e2 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let _conn = e2.connect(addr1.clone(), ECHO_ALPN).await?;
+        assert!(e2.connect(addr1, ECHO_ALPN).await.is_err());
+
+        assert_eq!(r1.metrics().incoming_accepted.get(), 1);
+        assert_eq!(r1.metrics().incoming_rate_limited.get(), 1);
+
+        r1.shutdown().await.e()?;
+        e2.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_global_handshake_rate_limit() -> Result {
+        let e1 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let r1 = Router::builder(e1)
+            .accept(ECHO_ALPN, Echo)
+            .global_handshake_rate_limit(HandshakeRateLimit::new(0, 1))
+            .spawn();
+        let addr1 = r1.endpoint().node_addr().initialized().await?;
+
+        // The limit applies across remotes.
+        let e2 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let e3 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let _conn = e2.connect(addr1.clone(), ECHO_ALPN).await?;
+        assert!(e3.connect(addr1, ECHO_ALPN).await.is_err());
+
+        assert_eq!(r1.metrics().incoming_accepted.get(), 1);
+        assert_eq!(r1.metrics().incoming_rate_limited.get(), 1);
+
+        r1.shutdown().await.e()?;
+        e2.close().await;
+        e3.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_pending_handshakes() -> Result {
+        /// Holds the handshake open until notified.
+        #[derive(Debug, Clone)]
+        struct SlowHandshake(Arc<tokio::sync::Notify>);
+
+        impl ProtocolHandler for SlowHandshake {
+            async fn on_connecting(
+                &self,
+                connecting: Connecting,
+            ) -> Result<Connection, AcceptError> {
+                self.0.notified().await;
+                Ok(connecting.await?)
+            }
+
+            async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+                connection.closed().await;
+                Ok(())
+            }
+        }
+
+        let notify = Arc::new(tokio::sync::Notify::new());
+        let e1 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        // Connections refused for too many pending handshakes do not count against the
+        // rate limit, two tokens are enough for the two accepted connections.
+        let r1 = Router::builder(e1)
+            .accept(ECHO_ALPN, SlowHandshake(notify.clone()))
+            .max_pending_handshakes(1)
+            .handshake_rate_limit(HandshakeRateLimit::new(0, 2))
+            .spawn();
+        let addr1 = r1.endpoint().node_addr().initialized().await?;
+
+        let e2 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let pending = tokio::spawn({
+            let e2 = e2.clone();
+            let addr1 = addr1.clone();
+            async move { e2.connect(addr1, ECHO_ALPN).await }
+        });
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while r1.metrics().incoming_accepted.get() == 0 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .e()?;
+
+        // The first handshake is still pending.
+        assert!(e2.connect(addr1.clone(), ECHO_ALPN).await.is_err());
+        assert_eq!(r1.metrics().incoming_connection_limited.get(), 1);
+
+        // Once the handshake completed, new connections are accepted again.
+        notify.notify_one();
+        let conn = pending.await.e()??;
+        notify.notify_one();
+        let conn2 = e2.connect(addr1, ECHO_ALPN).await?;
+        conn.close(0u32.into(), b"done");
+        conn2.close(0u32.into(), b"done");
+
+        r1.shutdown().await.e()?;
+        e2.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_connections() -> Result {
+        let e1 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let r1 = Router::builder(e1)
+            .accept(ECHO_ALPN, Echo)
+            .max_connections(1)
+            .spawn();
+        let addr1 = r1.endpoint().node_addr().initialized().await?;
+
+        let e2 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let conn = e2.connect(addr1.clone(), ECHO_ALPN).await?;
+        assert!(e2.connect(addr1.clone(), ECHO_ALPN).await.is_err());
+        assert_eq!(r1.metrics().incoming_connection_limited.get(), 1);
+
+        // Once the first connection is done, new connections are accepted again.
+        conn.close(0u32.into(), b"done");
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while e2.connect(addr1.clone(), ECHO_ALPN).await.is_err() {}
+        })
+        .await
+        .e()?;
+
+        r1.shutdown().await.e()?;
+        e2.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_connections_unbounded() -> Result {
+        let e1 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let r1 = Router::builder(e1)
+            .accept(ECHO_ALPN, Echo)
+            .max_pending_handshakes(usize::MAX)
+            .max_connections(usize::MAX)
+            .max_connections_for_alpn(ECHO_ALPN, usize::MAX)
+            .spawn();
+        let addr1 = r1.endpoint().node_addr().initialized().await?;
+
+        let e2 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let _conn = e2.connect(addr1, ECHO_ALPN).await?;
+
+        r1.shutdown().await.e()?;
+        e2.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_connections_for_alpn() -> Result {
+        let e1 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let r1 = Router::builder(e1)
+            .accept(ECHO_ALPN, Echo)
+            .max_connections_for_alpn(ECHO_ALPN, 1)
+            .handshake_rate_limit(HandshakeRateLimit::new(0, 3))
+            .spawn();
+        let addr1 = r1.endpoint().node_addr().initialized().await?;
+
+        let e2 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let _conn = e2.connect(addr1.clone(), ECHO_ALPN).await?;
+        for _ in 0..2 {
+            let conn = e2.connect(addr1.clone(), ECHO_ALPN).await?;
+            let reason = conn.closed().await;
+            assert_eq!(
+                reason,
+                ConnectionError::ApplicationClosed(ApplicationClose {
+                    error_code: 0u32.into(),
+                    reason: b"connection limit reached".to_vec().into()
+                })
+            );
+        }
+        assert_eq!(r1.metrics().incoming_connection_limited.get(), 2);
+
+        // Connections closed by the ALPN limit completed their handshake, so they used up
+        // the rate limit tokens.
+        assert!(e2.connect(addr1, ECHO_ALPN).await.is_err());
+        assert_eq!(r1.metrics().incoming_rate_limited.get(), 1);
+
+        r1.shutdown().await.e()?;
+        e2.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_add_remove_protocol() -> Result {
+        #[derive(Debug, Clone, Default)]
+        struct Tracked(Arc<AtomicBool>);
+
+        impl ProtocolHandler for Tracked {
+            async fn accept(&self, _connection: Connection) -> Result<(), AcceptError> {
+                Ok(())
+            }
+
+            async fn shutdown(&self) {
+                self.0.store(true, Ordering::SeqCst);
+            }
+        }
+
+        let e1 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let r1 = Router::builder(e1).spawn();
+        let addr1 = r1.endpoint().node_addr().initialized().await?;
+
+        let e2 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        assert!(e2.connect(addr1.clone(), ECHO_ALPN).await.is_err());
+
+        // Replacing a handler shuts it down.
+        let tracked = Tracked::default();
+        r1.add_protocol(ECHO_ALPN, tracked.clone()).await;
+        assert!(!tracked.0.load(Ordering::SeqCst));
+        r1.add_protocol(ECHO_ALPN, Echo).await;
+        assert!(tracked.0.load(Ordering::SeqCst));
+
+        let conn = e2.connect(addr1.clone(), ECHO_ALPN).await?;
+        let (mut send, mut recv) = conn.open_bi().await.e()?;
+        send.write_all(b"hello").await.e()?;
+        send.finish().e()?;
+        assert_eq!(recv.read_to_end(100).await.e()?, b"hello");
+
+        assert!(r1.remove_protocol(ECHO_ALPN).await);
+        assert!(!r1.remove_protocol(ECHO_ALPN).await);
+        assert!(e2.connect(addr1, ECHO_ALPN).await.is_err());
+
+        r1.shutdown().await.e()?;
+        e2.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_drain_timeout() -> Result {
+        let e1 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let r1 = Router::builder(e1)
+            .accept(ECHO_ALPN, Echo)
+            .drain_timeout(Duration::from_secs(10))
+            .spawn();
+        let addr1 = r1.endpoint().node_addr().initialized().await?;
+
+        let e2 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let conn = e2.connect(addr1.clone(), ECHO_ALPN).await?;
+        let (mut send, mut recv) = conn.open_bi().await.e()?;
+        send.write_all(b"hello").await.e()?;
+
+        let shutdown = tokio::spawn({
+            let r1 = r1.clone();
+            async move { r1.shutdown().await }
+        });
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!shutdown.is_finished());
+
+        // New connections are refused while draining.
+        assert!(e2.connect(addr1, ECHO_ALPN).await.is_err());
+
+        // The in-flight connection completes.
+        send.finish().e()?;
+        assert_eq!(recv.read_to_end(100).await.e()?, b"hello");
+        conn.close(0u32.into(), b"done");
+
+        tokio::time::timeout(Duration::from_secs(5), shutdown)
+            .await
+            .e()?
+            .e()?
+            .e()?;
+        assert!(r1.is_shutdown());
+        e2.close().await;
+        Ok(())
+    }
+
+    #[cfg(feature = "metrics-server")]
+    #[tokio::test]
+    async fn test_metrics_addr() -> Result {
+        let addr = std::net::TcpListener::bind("127.0.0.1:0")
+            .e()?
+            .local_addr()
+            .e()?;
+        let e1 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let r1 = Router::builder(e1)
+            .accept(ECHO_ALPN, Echo)
+            .metrics_addr(addr)
+            .spawn();
+        let addr1 = r1.endpoint().node_addr().initialized().await?;
+
+        let e2 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let conn = e2.connect(addr1, ECHO_ALPN).await?;
+        let (mut send, mut recv) = conn.open_bi().await.e()?;
+        send.write_all(b"hello").await.e()?;
+        send.finish().e()?;
+        recv.read_to_end(100).await.e()?;
+
+        let url = format!("http://{addr}/metrics");
+        let body = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Ok(res) = reqwest::get(&url).await {
+                    break res.text().await;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .e()?
+        .e()?;
+        assert!(body.contains("router_incoming_accepted_total 1"));
+        assert!(body.contains("magicsock_recv_datagrams_total"));
+
+        r1.shutdown().await.e()?;
+        e2.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_isolate_handler_panics() -> Result {
+        #[derive(Debug, Clone)]
+        struct Panicking;
+
+        impl ProtocolHandler for Panicking {
+            async fn accept(&self, _connection: Connection) -> Result<(), AcceptError> {
+                panic!("boom");
+            }
+        }
+
+        const PANIC_ALPN: &[u8] = b"/iroh/panic/1";
+
+        async fn trigger_panic(router: &Router, endpoint: &Endpoint) -> Result {
+            let addr = router.endpoint().node_addr().initialized().await?;
+            let panics = router.health().handler_panics;
+            let conn = endpoint.connect(addr, PANIC_ALPN).await?;
+            tokio::time::timeout(Duration::from_secs(5), async {
+                while router.health().handler_panics == panics {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+            .await
+            .e()?;
+            conn.close(0u32.into(), b"done");
+            Ok(())
+        }
+
+        let e2 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+
+        // By default a panic shuts down the router.
+        let e1 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let r1 = Router::builder(e1).accept(PANIC_ALPN, Panicking).spawn();
+        assert!(r1.health().running);
+        trigger_panic(&r1, &e2).await?;
+        assert!(r1.shutdown().await.is_ok());
+        let health = r1.health();
+        assert!(!health.running);
+        assert_eq!(health.exit_reason, Some(RouterExitReason::HandlerPanicked));
+        assert!(health.last_panic.unwrap().contains("boom"));
+
+        // With isolated panics the router keeps running.
+        let e1 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let r1 = Router::builder(e1)
+            .accept(PANIC_ALPN, Panicking)
+            .accept(ECHO_ALPN, Echo)
+            .isolate_handler_panics(true)
+            .spawn();
+        trigger_panic(&r1, &e2).await?;
+        assert!(r1.health().running);
+        assert_eq!(r1.metrics().handler_panics.get(), 1);
+
+        let addr1 = r1.endpoint().node_addr().initialized().await?;
+        let conn = e2.connect(addr1, ECHO_ALPN).await?;
+        let (mut send, mut recv) = conn.open_bi().await.e()?;
+        send.write_all(b"hello").await.e()?;
+        send.finish().e()?;
+        assert_eq!(recv.read_to_end(100).await.e()?, b"hello");
+        conn.close(0u32.into(), b"done");
+
+        r1.shutdown().await.e()?;
+        assert_eq!(r1.health().exit_reason, Some(RouterExitReason::Shutdown));
+
+        // With a restart policy the router resumes until the retries are exhausted.
+        let e1 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let r1 = Router::builder(e1)
+            .accept(PANIC_ALPN, Panicking)
+            .restart_on_handler_panic(
+                RestartPolicy::new(1).with_min_delay(Duration::from_millis(10)),
+            )
+            .spawn();
+        trigger_panic(&r1, &e2).await?;
+        let health = r1.health();
+        assert!(health.running);
+        assert_eq!(health.restarts, 1);
+
+        trigger_panic(&r1, &e2).await?;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while r1.health().running {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .e()?;
+        let health = r1.health();
+        assert_eq!(health.exit_reason, Some(RouterExitReason::HandlerPanicked));
+        assert_eq!(health.restarts, 1);
+        assert!(r1.shutdown().await.is_ok());
+
+        e2.close().await;
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_graceful_shutdown() -> Result {
         #[derive(Debug, Clone, Default)]
@@ -0,0 +1,329 @@
+//! Challenge/response proofs that a stream is bound to the remote node.
+//!
+//! The remote [`NodeId`] of a [`Connection`] is already authenticated by its TLS handshake.
+//! A [`Challenge`] lets custom protocols additionally check that the remote holds the
+//! node's [`SecretKey`] at the application level, e.g. after a stream has been handed
+//! through layers which do not have access to the connection's handshake:
+//!
+//! 1. The verifier creates a [`Challenge`] and sends it to the prover.
+//! 2. The prover signs it using [`Challenge::sign`] and sends back the [`Signature`].
+//! 3. The verifier checks it using [`Challenge::verify`].
+//!
+//! The signature covers keying material exported from the TLS session of the connection,
+//! with the challenge, the prover's [`Side`] of the connection and the prover's [`NodeId`]
+//! as context.  A signature can thus neither be replayed on any other connection, nor be
+//! reflected back to the node which created it.  [`prove`] and [`request_proof`] run this
+//! exchange over a bi-directional stream.
+
+use iroh_base::{NodeId, SecretKey, Signature, SignatureError};
+use nested_enum_utils::common_fields;
+use rand::RngCore;
+use snafu::{ResultExt, Snafu};
+
+use crate::endpoint::{
+    Connection, ExportKeyingMaterialError, ReadExactError, RecvStream, RemoteNodeIdError,
+    SendStream, Side, WriteError,
+};
+
+/// Label for exporting the keying material signed in a challenge.
+const EXPORT_LABEL: &[u8] = b"iroh-attestation-v1";
+
+/// A random challenge, to be signed by the prover.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Challenge([u8; 32]);
+
+impl Challenge {
+    /// The length of a challenge in bytes.
+    pub const LEN: usize = 32;
+
+    /// Creates a new random challenge.
+    pub fn new() -> Self {
+        let mut bytes = [0u8; Self::LEN];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        Self(bytes)
+    }
+
+    /// Creates a challenge from bytes received from the verifier.
+    pub fn from_bytes(bytes: [u8; Self::LEN]) -> Self {
+        Self(bytes)
+    }
+
+    /// Returns the bytes of this challenge, to be sent to the prover.
+    pub fn as_bytes(&self) -> &[u8; Self::LEN] {
+        &self.0
+    }
+
+    /// Signs this challenge for `connection` with the node's `secret_key`.
+    ///
+    /// `secret_key` must be the secret key of the local endpoint, the remote verifies the
+    /// signature against the [`NodeId`] it is connected to.
+    pub fn sign(
+        &self,
+        connection: &Connection,
+        secret_key: &SecretKey,
+    ) -> Result<Signature, AttestationError> {
+        let message = self.message(connection, connection.side(), secret_key.public())?;
+        Ok(secret_key.sign(&message))
+    }
+
+    /// Verifies that `signature` was created by [`Challenge::sign`] by the remote node of
+    /// `connection`.
+    pub fn verify(
+        &self,
+        connection: &Connection,
+        signature: &Signature,
+    ) -> Result<(), AttestationError> {
+        let remote = connection.remote_node_id().context(RemoteNodeIdSnafu)?;
+        let message = self.message(connection, !connection.side(), remote)?;
+        remote
+            .verify(&message, signature)
+            .context(InvalidSignatureSnafu)
+    }
+
+    /// Returns the message to sign, binding the challenge to the TLS session and prover.
+    fn message(
+        &self,
+        connection: &Connection,
+        prover_side: Side,
+        prover: NodeId,
+    ) -> Result<[u8; 32], AttestationError> {
+        let mut context = [0u8; 1 + 32 + Self::LEN];
+        context[0] = match prover_side {
+            Side::Client => 0,
+            Side::Server => 1,
+        };
+        context[1..33].copy_from_slice(prover.as_bytes());
+        context[33..].copy_from_slice(&self.0);
+        let mut message = [0u8; 32];
+        connection
+            .export_keying_material(&mut message, EXPORT_LABEL, &context)
+            .map_err(|err| ExportSnafu { err }.build())?;
+        Ok(message)
+    }
+}
+
+impl Default for Challenge {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Errors proving or verifying a node's identity.
+#[common_fields({
+    backtrace: Option<snafu::Backtrace>,
+    #[snafu(implicit)]
+    span_trace: n0_snafu::SpanTrace,
+})]
+#[allow(missing_docs)]
+#[derive(Debug, Snafu)]
+#[non_exhaustive]
+pub enum AttestationError {
+    #[snafu(display("Failed to export keying material: {err:?}"))]
+    Export { err: ExportKeyingMaterialError },
+    #[snafu(display("Remote node id is not available"))]
+    RemoteNodeId { source: RemoteNodeIdError },
+    #[snafu(display("Invalid signature"))]
+    InvalidSignature { source: SignatureError },
+    #[snafu(display("Failed to write to the stream"))]
+    Write { source: WriteError },
+    #[snafu(display("Failed to read from the stream"))]
+    Read { source: ReadExactError },
+}
+
+/// Proves the identity of this node to the remote, which runs [`request_proof`].
+///
+/// `secret_key` must be the secret key of the local endpoint.
+///
+/// Reads the challenge from `recv` and writes the signature to `send`.
+pub async fn prove(
+    connection: &Connection,
+    send: &mut SendStream,
+    recv: &mut RecvStream,
+    secret_key: &SecretKey,
+) -> Result<(), AttestationError> {
+    let mut challenge = [0u8; Challenge::LEN];
+    recv.read_exact(&mut challenge).await.context(ReadSnafu)?;
+    let signature = Challenge::from_bytes(challenge).sign(connection, secret_key)?;
+    send.write_all(&signature.to_bytes())
+        .await
+        .context(WriteSnafu)?;
+    Ok(())
+}
+
+/// Requests proof that the remote, which runs [`prove`], holds the secret key of its
+/// [`NodeId`].
+///
+/// Writes a new challenge to `send` and reads the signature from `recv`.
+pub async fn request_proof(
+    connection: &Connection,
+    send: &mut SendStream,
+    recv: &mut RecvStream,
+) -> Result<(), AttestationError> {
+    let challenge = Challenge::new();
+    send.write_all(challenge.as_bytes())
+        .await
+        .context(WriteSnafu)?;
+    let mut signature = [0u8; Signature::BYTE_SIZE];
+    recv.read_exact(&mut signature).await.context(ReadSnafu)?;
+    challenge.verify(connection, &Signature::from_bytes(&signature))
+}
+
+#[cfg(test)]
+mod tests {
+    use n0_snafu::{Result, ResultExt};
+    use n0_watcher::Watcher;
+
+    use super::*;
+    use crate::{Endpoint, RelayMode};
+
+    const ALPN: &[u8] = b"/iroh/test/attestation";
+
+    async fn connect() -> Result<(Endpoint, Endpoint, Connection, Connection)> {
+        let e1 = Endpoint::builder()
+            .alpns(vec![ALPN.to_vec()])
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let e2 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let addr1 = e1.node_addr().initialized().await?;
+        let (client, server) = tokio::join!(e2.connect(addr1, ALPN), async {
+            e1.accept().await.unwrap().await
+        });
+        Ok((e1, e2, client?, server.e()?))
+    }
+
+    #[tokio::test]
+    async fn test_challenge() -> Result {
+        let (e1, e2, client, server) = connect().await?;
+
+        let challenge = Challenge::new();
+        let signature = challenge.sign(&client, e2.secret_key())?;
+        challenge.verify(&server, &signature)?;
+
+        // The signature is only valid for the signed challenge.
+        let other = Challenge::new();
+        assert!(other.verify(&server, &signature).is_err());
+
+        // Only the remote's node key is accepted.
+        let other_key = SecretKey::generate(rand::thread_rng());
+        let signature = challenge.sign(&client, &other_key)?;
+        assert!(challenge.verify(&server, &signature).is_err());
+
+        // The signature can not be replayed on another connection.
+        let signature = challenge.sign(&client, e2.secret_key())?;
+        let (e3, e4, _client2, server2) = connect().await?;
+        assert!(challenge.verify(&server2, &signature).is_err());
+
+        for ep in [e1, e2, e3, e4] {
+            ep.close().await;
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_reflected_challenge() -> Result {
+        let (e1, e2, client, server) = connect().await?;
+
+        // The server asks the client to prove its identity.  A malicious client sends the
+        // same challenge back to the server, and replies with the server's signature.
+        let challenge = Challenge::new();
+        let reflected = challenge.sign(&server, e1.secret_key())?;
+        assert!(challenge.verify(&server, &reflected).is_err());
+
+        // The same holds in the other direction.
+        let reflected = challenge.sign(&client, e2.secret_key())?;
+        assert!(challenge.verify(&client, &reflected).is_err());
+
+        e1.close().await;
+        e2.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prove() -> Result {
+        let (e1, e2, client, server) = connect().await?;
+
+        let (mut client_send, mut client_recv) = client.open_bi().await.e()?;
+        // Streams are only announced to the remote once data is sent.
+        client_send.write_all(b"x").await.e()?;
+        let (mut server_send, mut server_recv) = server.accept_bi().await.e()?;
+        let mut buf = [0u8; 1];
+        server_recv.read_exact(&mut buf).await.e()?;
+
+        let (proved, verified) = tokio::join!(
+            prove(&client, &mut client_send, &mut client_recv, e2.secret_key()),
+            request_proof(&server, &mut server_send, &mut server_recv),
+        );
+        proved?;
+        verified?;
+
+        let other_key = SecretKey::generate(rand::thread_rng());
+        let (proved, verified) = tokio::join!(
+            prove(&client, &mut client_send, &mut client_recv, &other_key),
+            request_proof(&server, &mut server_send, &mut server_recv),
+        );
+        proved?;
+        assert!(matches!(
+            verified,
+            Err(AttestationError::InvalidSignature { .. })
+        ));
+
+        e1.close().await;
+        e2.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_request_proof_in_router() -> Result {
+        use crate::protocol::{AcceptError, ProtocolHandler, Router};
+
+        #[derive(Debug, Clone)]
+        struct Verifier(tokio::sync::mpsc::Sender<(Side, bool)>);
+
+        impl ProtocolHandler for Verifier {
+            async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+                let (mut send, mut recv) = connection.accept_bi().await?;
+                let mut buf = [0u8; 1];
+                recv.read_exact(&mut buf)
+                    .await
+                    .map_err(AcceptError::from_err)?;
+                let verified = request_proof(&connection, &mut send, &mut recv).await;
+                self.0
+                    .send((connection.side(), verified.is_ok()))
+                    .await
+                    .ok();
+                connection.closed().await;
+                Ok(())
+            }
+        }
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel(1);
+        let e1 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let router = Router::builder(e1).accept(ALPN, Verifier(tx)).spawn();
+        let addr1 = router.endpoint().node_addr().initialized().await?;
+        let e2 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+
+        let client = e2.connect(addr1, ALPN).await?;
+        assert_eq!(client.side(), Side::Client);
+        let (mut send, mut recv) = client.open_bi().await.e()?;
+        send.write_all(b"x").await.e()?;
+        prove(&client, &mut send, &mut recv, e2.secret_key()).await?;
+        let (side, verified) = rx.recv().await.e()?;
+        assert_eq!(side, Side::Server);
+        assert!(verified);
+
+        client.close(0u32.into(), b"done");
+        router.shutdown().await.e()?;
+        e2.close().await;
+        Ok(())
+    }
+}
@@ -0,0 +1,465 @@
+//! Application level pings to measure round trip times and track the liveness of peers.
+//!
+//! Register the [`Ping`] protocol handler for [`ALPN`] on the [`Router`] of the nodes that
+//! should answer pings.  A single ping is sent using [`ping`], or [`ping_connection`] to
+//! reuse an existing connection.  To continuously track peers use the [`LivenessTracker`].
+//!
+//! ```no_run
+//! # use iroh::{protocol::{ping::{self, Ping}, Router}, Endpoint, NodeAddr};
+//! # async fn wrapper(remote: NodeAddr) -> n0_snafu::Result {
+//! let endpoint = Endpoint::builder().discovery_n0().bind().await?;
+//! let router = Router::builder(endpoint.clone())
+//!     .accept(ping::ALPN, Ping)
+//!     .spawn();
+//!
+//! let rtt = ping::ping(&endpoint, remote).await?;
+//! println!("round trip time: {rtt:?}");
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`Router`]: super::Router
+
+use std::{
+    collections::{BTreeMap, VecDeque},
+    sync::{Arc, Mutex},
+};
+
+use iroh_base::{NodeAddr, NodeId};
+use n0_future::{
+    task::{self, AbortOnDropHandle},
+    time::{self, Duration, Instant, SystemTime},
+};
+use nested_enum_utils::common_fields;
+use snafu::{ensure, ResultExt, Snafu};
+use tracing::{debug, info_span, Instrument};
+
+use super::{AcceptError, ProtocolHandler};
+use crate::{
+    endpoint::{
+        ClosedStream, ConnectError, Connection, ConnectionError, ReadToEndError, WriteError,
+    },
+    Endpoint,
+};
+
+/// The ALPN of the ping protocol.
+pub const ALPN: &[u8] = b"/iroh/ping/0";
+
+/// The payload sent with each ping, and echoed back by the remote.
+const PAYLOAD: &[u8] = b"ping";
+
+/// The [`ProtocolHandler`] answering pings.
+#[derive(Debug, Clone, Default)]
+pub struct Ping;
+
+impl ProtocolHandler for Ping {
+    async fn accept(&self, connection: Connection) -> Result<(), AcceptError> {
+        loop {
+            let (mut send, mut recv) = match connection.accept_bi().await {
+                Ok(streams) => streams,
+                Err(ConnectionError::ApplicationClosed(_) | ConnectionError::LocallyClosed) => {
+                    return Ok(());
+                }
+                Err(err) => return Err(err.into()),
+            };
+            let payload = recv
+                .read_to_end(PAYLOAD.len())
+                .await
+                .map_err(AcceptError::from_err)?;
+            send.write_all(&payload)
+                .await
+                .map_err(AcceptError::from_err)?;
+            send.finish().map_err(AcceptError::from_err)?;
+        }
+    }
+}
+
+/// Errors sending a ping.
+#[common_fields({
+    backtrace: Option<snafu::Backtrace>,
+    #[snafu(implicit)]
+    span_trace: n0_snafu::SpanTrace,
+})]
+#[allow(missing_docs)]
+#[derive(Debug, Snafu)]
+#[non_exhaustive]
+pub enum PingError {
+    #[snafu(display("Failed to connect"))]
+    Connect { source: ConnectError },
+    #[snafu(display("Failed to open a stream"))]
+    Open { source: ConnectionError },
+    #[snafu(display("Failed to send the ping"))]
+    Write { source: WriteError },
+    #[snafu(display("Failed to finish the stream"))]
+    Finish { source: ClosedStream },
+    #[snafu(display("Failed to receive the pong"))]
+    Read { source: ReadToEndError },
+    #[snafu(display("The remote answered with an invalid pong"))]
+    InvalidPong {},
+}
+
+/// Connects to `node_addr` and measures the round trip time of a single ping.
+///
+/// The remote must accept the [`Ping`] protocol.  The connection is closed afterwards, to
+/// ping repeatedly use [`ping_connection`] or the [`LivenessTracker`].
+pub async fn ping(
+    endpoint: &Endpoint,
+    node_addr: impl Into<NodeAddr>,
+) -> Result<Duration, PingError> {
+    let connection = endpoint
+        .connect(node_addr, ALPN)
+        .await
+        .context(ConnectSnafu)?;
+    let rtt = ping_connection(&connection).await;
+    connection.close(0u32.into(), b"done");
+    rtt
+}
+
+/// Measures the round trip time of a single ping over an existing connection.
+///
+/// The connection must have been established using the [`ALPN`] of the ping protocol.
+pub async fn ping_connection(connection: &Connection) -> Result<Duration, PingError> {
+    let start = Instant::now();
+    let (mut send, mut recv) = connection.open_bi().await.context(OpenSnafu)?;
+    send.write_all(PAYLOAD).await.context(WriteSnafu)?;
+    send.finish().context(FinishSnafu)?;
+    let pong = recv.read_to_end(PAYLOAD.len()).await.context(ReadSnafu)?;
+    ensure!(pong == PAYLOAD, InvalidPongSnafu);
+    Ok(start.elapsed())
+}
+
+/// The outcome of a ping sent by the [`LivenessTracker`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PingRecord {
+    /// When the ping was sent.
+    pub time: SystemTime,
+    /// The round trip time, or `None` if the ping failed.
+    pub rtt: Option<Duration>,
+}
+
+/// Periodically pings a set of peers and keeps a history of the results.
+///
+/// Each tracked peer is pinged every [`LivenessTracker::interval`] over a connection which
+/// is kept open between pings, and re-established when it was lost.  A ping that takes
+/// longer than [`LivenessTracker::timeout`] counts as failed.  The last
+/// [`LivenessTracker::history_len`] results are kept per peer.
+///
+/// Dropping the last clone of the tracker stops all pings.
+#[derive(Debug, Clone)]
+pub struct LivenessTracker {
+    endpoint: Endpoint,
+    interval: Duration,
+    timeout: Duration,
+    history_len: usize,
+    peers: Arc<Mutex<BTreeMap<NodeId, Peer>>>,
+}
+
+#[derive(Debug)]
+struct Peer {
+    history: VecDeque<PingRecord>,
+    _task: AbortOnDropHandle<()>,
+}
+
+impl LivenessTracker {
+    /// The default interval between pings.
+    pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(15);
+
+    /// The default time after which a ping counts as failed.
+    pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// The default number of results kept per peer.
+    pub const DEFAULT_HISTORY_LEN: usize = 240;
+
+    /// Creates a new tracker sending pings from `endpoint`.
+    pub fn new(endpoint: Endpoint) -> Self {
+        Self {
+            endpoint,
+            interval: Self::DEFAULT_INTERVAL,
+            timeout: Self::DEFAULT_TIMEOUT,
+            history_len: Self::DEFAULT_HISTORY_LEN,
+            peers: Default::default(),
+        }
+    }
+
+    /// Sets the interval between pings.
+    ///
+    /// Only affects peers tracked afterwards.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+
+    /// Sets the time after which a ping counts as failed.
+    ///
+    /// This includes the time to establish a connection.  Only affects peers tracked
+    /// afterwards.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Sets the number of results kept per peer.
+    ///
+    /// With a length of zero no results are kept, and peers are pinged without recording
+    /// the results.
+    pub fn history_len(mut self, history_len: usize) -> Self {
+        self.history_len = history_len;
+        self
+    }
+
+    /// Starts pinging a peer.
+    ///
+    /// If the peer is already tracked, its history is kept and its pings are restarted
+    /// using `node_addr`.
+    pub fn track(&self, node_addr: impl Into<NodeAddr>) {
+        let node_addr = node_addr.into();
+        let node_id = node_addr.node_id;
+        let span = info_span!("liveness", remote = %node_id.fmt_short());
+        // The task stops once the peer is not in the map anymore, so we spawn it while
+        // holding the lock and insert it before the task can check.
+        let mut peers = self.peers.lock().expect("poisoned");
+        let task = task::spawn(
+            run_pings(
+                self.endpoint.clone(),
+                node_addr,
+                self.interval,
+                self.timeout,
+                self.history_len,
+                Arc::downgrade(&self.peers),
+            )
+            .instrument(span),
+        );
+        let task = AbortOnDropHandle::new(task);
+        match peers.get_mut(&node_id) {
+            Some(peer) => peer._task = task,
+            None => {
+                peers.insert(
+                    node_id,
+                    Peer {
+                        history: VecDeque::new(),
+                        _task: task,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Stops pinging a peer and drops its history.
+    ///
+    /// Returns `false` if the peer was not tracked.
+    pub fn untrack(&self, node_id: &NodeId) -> bool {
+        self.peers
+            .lock()
+            .expect("poisoned")
+            .remove(node_id)
+            .is_some()
+    }
+
+    /// Returns the tracked peers.
+    pub fn peers(&self) -> Vec<NodeId> {
+        self.peers
+            .lock()
+            .expect("poisoned")
+            .keys()
+            .copied()
+            .collect()
+    }
+
+    /// Returns the ping results of a peer, oldest first.
+    ///
+    /// Returns `None` if the peer is not tracked.
+    pub fn history(&self, node_id: &NodeId) -> Option<Vec<PingRecord>> {
+        let peers = self.peers.lock().expect("poisoned");
+        peers
+            .get(node_id)
+            .map(|peer| peer.history.iter().copied().collect())
+    }
+
+    /// Returns the most recent round trip time to a peer.
+    ///
+    /// Returns `None` if the peer is not tracked, was not pinged yet, or its last ping
+    /// failed.
+    pub fn latest_rtt(&self, node_id: &NodeId) -> Option<Duration> {
+        let peers = self.peers.lock().expect("poisoned");
+        peers.get(node_id)?.history.back()?.rtt
+    }
+}
+
+async fn run_pings(
+    endpoint: Endpoint,
+    node_addr: NodeAddr,
+    interval: Duration,
+    timeout: Duration,
+    history_len: usize,
+    peers: std::sync::Weak<Mutex<BTreeMap<NodeId, Peer>>>,
+) {
+    let node_id = node_addr.node_id;
+    let mut connection: Option<Connection> = None;
+    loop {
+        let time = SystemTime::now();
+        let attempt = async {
+            let conn = match connection.take() {
+                Some(conn) if conn.close_reason().is_none() => conn,
+                _ => endpoint
+                    .connect(node_addr.clone(), ALPN)
+                    .await
+                    .context(ConnectSnafu)?,
+            };
+            let rtt = ping_connection(&conn).await?;
+            connection = Some(conn);
+            Ok::<_, PingError>(rtt)
+        };
+        let rtt = match time::timeout(timeout, attempt).await {
+            Ok(Ok(rtt)) => Some(rtt),
+            Ok(Err(err)) => {
+                debug!("ping failed: {err:#}");
+                None
+            }
+            Err(_) => {
+                debug!("ping timed out");
+                connection = None;
+                None
+            }
+        };
+
+        let Some(peers) = peers.upgrade() else {
+            break;
+        };
+        {
+            let mut peers = peers.lock().expect("poisoned");
+            let Some(peer) = peers.get_mut(&node_id) else {
+                break;
+            };
+            peer.history.push_back(PingRecord { time, rtt });
+            while peer.history.len() > history_len {
+                peer.history.pop_front();
+            }
+        }
+        drop(peers);
+
+        time::sleep(interval).await;
+    }
+    if let Some(conn) = connection {
+        conn.close(0u32.into(), b"done");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use n0_snafu::{Result, ResultExt};
+    use n0_watcher::Watcher;
+
+    use super::*;
+    use crate::{protocol::Router, RelayMode};
+
+    #[tokio::test]
+    async fn test_ping() -> Result {
+        let e1 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let r1 = Router::builder(e1).accept(ALPN, Ping).spawn();
+        let addr1 = r1.endpoint().node_addr().initialized().await?;
+
+        let e2 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let rtt = ping(&e2, addr1.clone()).await?;
+        assert!(rtt < Duration::from_secs(5));
+
+        let conn = e2.connect(addr1, ALPN).await?;
+        for _ in 0..3 {
+            ping_connection(&conn).await?;
+        }
+        conn.close(0u32.into(), b"done");
+
+        r1.shutdown().await.e()?;
+        e2.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_liveness_tracker() -> Result {
+        let e1 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let r1 = Router::builder(e1).accept(ALPN, Ping).spawn();
+        let addr1 = r1.endpoint().node_addr().initialized().await?;
+        let node_id = addr1.node_id;
+
+        let e2 = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let tracker = LivenessTracker::new(e2.clone())
+            .interval(Duration::from_millis(50))
+            .timeout(Duration::from_millis(500))
+            .history_len(3);
+        tracker.track(addr1);
+        assert_eq!(tracker.peers(), vec![node_id]);
+
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while tracker.history(&node_id).unwrap_or_default().len() < 3 {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .e()?;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+
+        let history = tracker.history(&node_id).unwrap();
+        assert_eq!(history.len(), 3);
+        assert!(tracker.latest_rtt(&node_id).is_some());
+
+        // Pings fail once the remote is gone.
+        r1.shutdown().await.e()?;
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while tracker.latest_rtt(&node_id).is_some() {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .e()?;
+
+        assert!(tracker.untrack(&node_id));
+        assert!(tracker.history(&node_id).is_none());
+        e2.close().await;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_liveness_tracker_failures() -> Result {
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        // Without any addresses connecting fails right away.
+        let node_id = iroh_base::SecretKey::generate(rand::thread_rng()).public();
+        let tracker = LivenessTracker::new(ep.clone()).interval(Duration::from_millis(10));
+        tracker.track(node_id);
+
+        tokio::time::timeout(Duration::from_secs(10), async {
+            while tracker.history(&node_id).unwrap_or_default().len() < 3 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .e()?;
+        let history = tracker.history(&node_id).unwrap();
+        assert!(history.iter().all(|record| record.rtt.is_none()));
+
+        // No results are kept with a history length of zero.
+        let tracker = LivenessTracker::new(ep.clone())
+            .interval(Duration::from_millis(10))
+            .history_len(0);
+        tracker.track(node_id);
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(tracker.peers(), vec![node_id]);
+        assert_eq!(tracker.history(&node_id), Some(vec![]));
+
+        ep.close().await;
+        Ok(())
+    }
+}
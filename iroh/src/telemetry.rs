@@ -0,0 +1,396 @@
+//! Opt-in reporting of anonymized, aggregate endpoint statistics.
+//!
+//! Nothing in this module runs unless explicitly started using [`Telemetry::spawn`].  Once
+//! started, a [`TelemetryReport`] is sent every [`TelemetryConfig::interval`] to the
+//! configured URL, allowing operators to aggregate the health of a fleet of nodes.
+//!
+//! # Report schema
+//!
+//! Each report is sent as the JSON body of an HTTP `POST` request.  The fields are
+//! documented on [`TelemetryReport`], an example report looks like this:
+//!
+//! ```json
+//! {
+//!   "schema": 1,
+//!   "version": "0.35.0",
+//!   "period_secs": 3600,
+//!   "connections": 12,
+//!   "connections_direct": 9,
+//!   "holepunch_success_rate": 0.75,
+//!   "nodes_contacted": 4,
+//!   "nodes_contacted_directly": 3,
+//!   "datagrams_sent_direct": 81234,
+//!   "datagrams_sent_relay": 2345,
+//!   "datagrams_recv_direct": 79821,
+//!   "datagrams_recv_relay": 2210
+//! }
+//! ```
+//!
+//! All counters are the totals accumulated during the reporting period, not since the
+//! endpoint was created.  The reports never contain [`NodeId`]s, IP addresses, relay URLs
+//! or any other information identifying the node or its peers.
+//!
+//! Incompatible changes to the report will increase [`TELEMETRY_SCHEMA_VERSION`].
+//!
+//! [`NodeId`]: crate::NodeId
+
+use std::sync::Arc;
+
+use iroh_metrics::Counter;
+use n0_future::{
+    task::{self, AbortOnDropHandle},
+    time::{self, Duration, Instant},
+};
+use nested_enum_utils::common_fields;
+use serde::{Deserialize, Serialize};
+use snafu::{ResultExt, Snafu};
+use tracing::{debug, error_span, warn, Instrument};
+use url::Url;
+
+use crate::{metrics::MagicsockMetrics, Endpoint};
+
+/// The version of the [`TelemetryReport`] schema.
+pub const TELEMETRY_SCHEMA_VERSION: u32 = 1;
+
+/// The default interval at which reports are sent: 1 hour.
+pub const DEFAULT_TELEMETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// The timeout for sending a single report.
+#[cfg(not(wasm_browser))]
+const TELEMETRY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Configuration for [`Telemetry`].
+#[derive(Debug, Clone)]
+pub struct TelemetryConfig {
+    url: Url,
+    interval: Duration,
+}
+
+impl TelemetryConfig {
+    /// Creates a new configuration reporting to `url`.
+    ///
+    /// Reports are sent every [`DEFAULT_TELEMETRY_INTERVAL`].
+    pub fn new(url: Url) -> Self {
+        Self {
+            url,
+            interval: DEFAULT_TELEMETRY_INTERVAL,
+        }
+    }
+
+    /// Sets the interval at which reports are sent.
+    pub fn interval(mut self, interval: Duration) -> Self {
+        self.interval = interval;
+        self
+    }
+}
+
+/// A single telemetry report, see the [module docs] for the wire format.
+///
+/// [module docs]: crate::telemetry
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[non_exhaustive]
+pub struct TelemetryReport {
+    /// The schema version of this report, see [`TELEMETRY_SCHEMA_VERSION`].
+    pub schema: u32,
+    /// The version of iroh running on the node.
+    pub version: String,
+    /// The duration covered by this report, in seconds.
+    pub period_secs: u64,
+    /// Number of connections with a successful handshake.
+    pub connections: u64,
+    /// Number of connections which became direct.
+    ///
+    /// This includes connections established during earlier periods, so it can be larger
+    /// than `connections`.
+    pub connections_direct: u64,
+    /// The ratio of `connections_direct` to `connections`, at most `1.0`.
+    ///
+    /// `None` if there were no connections during this period.
+    pub holepunch_success_rate: Option<f64>,
+    /// Number of nodes we attempted to contact.
+    pub nodes_contacted: u64,
+    /// Number of nodes we managed to contact directly.
+    pub nodes_contacted_directly: u64,
+    /// Number of datagrams sent over direct IPv4 and IPv6 paths.
+    pub datagrams_sent_direct: u64,
+    /// Number of datagrams sent via relay servers.
+    pub datagrams_sent_relay: u64,
+    /// Number of data datagrams received over direct IPv4 and IPv6 paths.
+    pub datagrams_recv_direct: u64,
+    /// Number of data datagrams received via relay servers.
+    pub datagrams_recv_relay: u64,
+}
+
+#[allow(missing_docs)]
+#[common_fields({
+    backtrace: Option<snafu::Backtrace>,
+    #[snafu(implicit)]
+    span_trace: n0_snafu::SpanTrace,
+})]
+#[derive(Debug, Snafu)]
+#[non_exhaustive]
+pub enum TelemetryError {
+    #[snafu(display("Failed to encode report"))]
+    Encode { source: serde_json::Error },
+    #[snafu(display("Error sending http request"))]
+    HttpSend { source: reqwest::Error },
+    #[snafu(display("Error response to http request"))]
+    HttpRequest { status: reqwest::StatusCode },
+}
+
+/// Periodically reports aggregate statistics of an [`Endpoint`].
+///
+/// Reporting stops once this and all its clones are dropped, or the endpoint is closed.
+#[derive(Debug, Clone)]
+pub struct Telemetry {
+    _drop_guard: Arc<AbortOnDropHandle<()>>,
+}
+
+impl Telemetry {
+    /// Starts reporting statistics of the `endpoint` as configured by `config`.
+    ///
+    /// The first report is sent after one interval elapsed.
+    pub fn spawn(endpoint: &Endpoint, config: TelemetryConfig) -> Self {
+        debug!(url = %config.url, interval = ?config.interval, "starting telemetry");
+        let metrics = endpoint.metrics().magicsock.clone();
+        #[allow(unused_mut)]
+        let mut http_client = reqwest::Client::builder();
+        #[cfg(not(wasm_browser))]
+        {
+            http_client = http_client.timeout(TELEMETRY_TIMEOUT);
+        }
+        let service = TelemetryService {
+            http_client: http_client.build().expect("valid client config"),
+            last: Snapshot::new(&metrics),
+            last_report: Instant::now(),
+            metrics,
+            config,
+        };
+        let cancel = endpoint.cancel_token().clone();
+        let handle = task::spawn(
+            async move {
+                cancel.run_until_cancelled(service.run()).await;
+                debug!("stopped telemetry");
+            }
+            .instrument(error_span!("telemetry")),
+        );
+        Self {
+            _drop_guard: Arc::new(AbortOnDropHandle::new(handle)),
+        }
+    }
+}
+
+/// The values of the counters we report at a point in time.
+#[derive(Debug, Clone, Copy)]
+struct Snapshot {
+    connections: u64,
+    connections_direct: u64,
+    nodes_contacted: u64,
+    nodes_contacted_directly: u64,
+    datagrams_sent_direct: u64,
+    datagrams_sent_relay: u64,
+    datagrams_recv_direct: u64,
+    datagrams_recv_relay: u64,
+}
+
+impl Snapshot {
+    fn new(metrics: &MagicsockMetrics) -> Self {
+        Self {
+            connections: metrics.connection_handshake_success.get(),
+            connections_direct: metrics.connection_became_direct.get(),
+            nodes_contacted: metrics.nodes_contacted.get(),
+            nodes_contacted_directly: metrics.nodes_contacted_directly.get(),
+            datagrams_sent_direct: sum(&[&metrics.send_ipv4, &metrics.send_ipv6]),
+            datagrams_sent_relay: metrics.send_relay.get(),
+            datagrams_recv_direct: sum(&[&metrics.recv_data_ipv4, &metrics.recv_data_ipv6]),
+            datagrams_recv_relay: metrics.recv_data_relay.get(),
+        }
+    }
+
+    /// Builds the report for the period from `earlier` to `self`.
+    fn report_since(&self, earlier: &Snapshot, period: Duration) -> TelemetryReport {
+        let connections = self.connections.saturating_sub(earlier.connections);
+        let connections_direct = self
+            .connections_direct
+            .saturating_sub(earlier.connections_direct);
+        // Connections can become direct after the period they were established in.
+        let holepunch_success_rate =
+            (connections > 0).then(|| (connections_direct as f64 / connections as f64).min(1.0));
+        TelemetryReport {
+            schema: TELEMETRY_SCHEMA_VERSION,
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            period_secs: period.as_secs(),
+            connections,
+            connections_direct,
+            holepunch_success_rate,
+            nodes_contacted: self.nodes_contacted.saturating_sub(earlier.nodes_contacted),
+            nodes_contacted_directly: self
+                .nodes_contacted_directly
+                .saturating_sub(earlier.nodes_contacted_directly),
+            datagrams_sent_direct: self
+                .datagrams_sent_direct
+                .saturating_sub(earlier.datagrams_sent_direct),
+            datagrams_sent_relay: self
+                .datagrams_sent_relay
+                .saturating_sub(earlier.datagrams_sent_relay),
+            datagrams_recv_direct: self
+                .datagrams_recv_direct
+                .saturating_sub(earlier.datagrams_recv_direct),
+            datagrams_recv_relay: self
+                .datagrams_recv_relay
+                .saturating_sub(earlier.datagrams_recv_relay),
+        }
+    }
+}
+
+fn sum(counters: &[&Counter]) -> u64 {
+    counters.iter().map(|c| c.get()).sum()
+}
+
+#[derive(Debug)]
+struct TelemetryService {
+    http_client: reqwest::Client,
+    metrics: Arc<MagicsockMetrics>,
+    config: TelemetryConfig,
+    /// The counters as of the last successful report.
+    last: Snapshot,
+    last_report: Instant,
+}
+
+impl TelemetryService {
+    async fn run(mut self) {
+        let mut interval =
+            time::interval_at(Instant::now() + self.config.interval, self.config.interval);
+        loop {
+            interval.tick().await;
+            let now = Snapshot::new(&self.metrics);
+            let report = now.report_since(&self.last, self.last_report.elapsed());
+            match self.send(&report).await {
+                Ok(()) => {
+                    debug!(?report, "sent telemetry report");
+                    self.last = now;
+                    self.last_report = Instant::now();
+                }
+                Err(err) => {
+                    // Keep the last snapshot, so the next report covers this period as well.
+                    warn!(url = %self.config.url, "Failed to send telemetry report: {err:#}");
+                }
+            }
+        }
+    }
+
+    async fn send(&self, report: &TelemetryReport) -> Result<(), TelemetryError> {
+        let body = serde_json::to_vec(report).context(EncodeSnafu)?;
+        let response = self
+            .http_client
+            .post(self.config.url.clone())
+            .header(http::header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await
+            .context(HttpSendSnafu)?;
+        if !response.status().is_success() {
+            return Err(HttpRequestSnafu {
+                status: response.status(),
+            }
+            .build());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{Ipv4Addr, SocketAddr};
+
+    use axum::{extract::State, routing::post, Json, Router};
+    use n0_snafu::{Result, ResultExt};
+    use tokio::sync::mpsc;
+
+    use super::*;
+    use crate::RelayMode;
+
+    #[tokio::test]
+    async fn test_telemetry_report() -> Result {
+        let (tx, mut rx) = mpsc::channel(8);
+        let app = Router::new()
+            .route(
+                "/report",
+                post(
+                    |State(tx): State<mpsc::Sender<TelemetryReport>>,
+                     Json(report): Json<TelemetryReport>| async move {
+                        tx.send(report).await.ok();
+                    },
+                ),
+            )
+            .with_state(tx);
+        let listener = tokio::net::TcpListener::bind(SocketAddr::from((Ipv4Addr::LOCALHOST, 0)))
+            .await
+            .e()?;
+        let addr = listener.local_addr().e()?;
+        let _server = AbortOnDropHandle::new(tokio::spawn(async move {
+            axum::serve(listener, app).await.ok();
+        }));
+
+        let ep = Endpoint::builder()
+            .relay_mode(RelayMode::Disabled)
+            .bind()
+            .await?;
+        let url = format!("http://{addr}/report").parse::<Url>().e()?;
+        let config = TelemetryConfig::new(url).interval(Duration::from_millis(200));
+        let _telemetry = Telemetry::spawn(&ep, config);
+
+        let metrics = &ep.metrics().magicsock;
+        metrics.connection_handshake_success.inc_by(4);
+        metrics.connection_became_direct.inc_by(3);
+
+        let report = time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .e()?
+            .expect("server running");
+        assert_eq!(report.schema, TELEMETRY_SCHEMA_VERSION);
+        assert_eq!(report.version, env!("CARGO_PKG_VERSION"));
+        assert_eq!(report.connections, 4);
+        assert_eq!(report.connections_direct, 3);
+        assert_eq!(report.holepunch_success_rate, Some(0.75));
+
+        // The next report only contains what happened since the first one.
+        let report = time::timeout(Duration::from_secs(5), rx.recv())
+            .await
+            .e()?
+            .expect("server running");
+        assert_eq!(report.connections, 0);
+        assert_eq!(report.holepunch_success_rate, None);
+
+        // Reporting stops once the endpoint is closed.
+        ep.close().await;
+        time::sleep(Duration::from_millis(100)).await;
+        while rx.try_recv().is_ok() {}
+        time::sleep(Duration::from_millis(600)).await;
+        assert!(rx.try_recv().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_holepunch_success_rate_capped() {
+        let zero = Snapshot {
+            connections: 0,
+            connections_direct: 0,
+            nodes_contacted: 0,
+            nodes_contacted_directly: 0,
+            datagrams_sent_direct: 0,
+            datagrams_sent_relay: 0,
+            datagrams_recv_direct: 0,
+            datagrams_recv_relay: 0,
+        };
+        // Connections from an earlier period became direct during this one.
+        let now = Snapshot {
+            connections: 2,
+            connections_direct: 5,
+            ..zero
+        };
+        let report = now.report_since(&zero, Duration::from_secs(1));
+        assert_eq!(report.connections_direct, 5);
+        assert_eq!(report.holepunch_success_rate, Some(1.0));
+    }
+}
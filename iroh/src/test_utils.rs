@@ -5,8 +5,8 @@ pub use dns_and_pkarr_servers::DnsPkarrServer;
 use iroh_base::RelayUrl;
 use iroh_relay::{
     server::{
-        AccessConfig, CertConfig, QuicConfig, RelayConfig, Server, ServerConfig, SpawnError,
-        StunConfig, TlsConfig,
+        AccessConfig, CertConfig, Limits, QuicConfig, RelayConfig, Server, ServerConfig,
+        SpawnError, StunConfig, TlsConfig,
     },
     RelayMap, RelayNode, RelayQuicConfig,
 };
@@ -59,11 +59,25 @@ pub async fn run_relay_server_with_stun() -> Result<(RelayMap, RelayUrl, Server)
 ///
 /// If `quic` is set to `true`, it will make the appropriate [`QuicConfig`] from the generated tls certificates and run the quic server at a random free port.
 ///
-///
 /// The return value is similar to [`run_relay_server`].
 pub async fn run_relay_server_with(
     stun: Option<StunConfig>,
     quic: bool,
+) -> Result<(RelayMap, RelayUrl, Server), SpawnError> {
+    run_relay_server_with_limits(stun, quic, Limits::default()).await
+}
+
+/// Runs a relay server with the given [`Limits`].
+///
+/// Like [`run_relay_server_with`], but `limits` configures the relay's [`Limits`].  Use
+/// [`Limits::traffic_shaping`] to simulate latency, jitter and packet loss on relayed packets,
+/// and [`Limits::client_rx`] to cap the bandwidth of each client.
+///
+/// The return value is similar to [`run_relay_server`].
+pub async fn run_relay_server_with_limits(
+    stun: Option<StunConfig>,
+    quic: bool,
+    limits: Limits,
 ) -> Result<(RelayMap, RelayUrl, Server), SpawnError> {
     let (certs, server_config) = iroh_relay::server::testing::self_signed_tls_certs_and_config();
 
@@ -85,7 +99,7 @@ pub async fn run_relay_server_with(
         relay: Some(RelayConfig {
             http_bind_addr: (Ipv4Addr::LOCALHOST, 0).into(),
             tls: Some(tls),
-            limits: Default::default(),
+            limits,
             key_cache_capacity: Some(1024),
             access: AccessConfig::Everyone,
         }),
@@ -106,6 +120,7 @@ pub async fn run_relay_server_with(
         stun_only: false,
         stun_port: server.stun_addr().map_or(DEFAULT_STUN_PORT, |s| s.port()),
         quic,
+        tls: Default::default(),
     }
     .into();
     Ok((n, url, server))